@@ -0,0 +1,137 @@
+use http::{header::CONTENT_TYPE, Method};
+use hyper::{Body, Request, Response};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tracing::info;
+
+use crate::{auth, config::CacheStoreKind, error::RequestError, State};
+
+#[derive(Serialize)]
+struct ReadOnlyStatus {
+    read_only: bool,
+}
+
+#[derive(Serialize)]
+struct CacheStatus {
+    store: CacheStoreKind,
+    /// Amount of cached responses per route.
+    entries: BTreeMap<String, usize>,
+    bytes: usize,
+}
+
+#[derive(Serialize)]
+struct CacheEntry {
+    key: String,
+    route: String,
+    path: String,
+    status: u16,
+    size: usize,
+    /// Seconds since the response was cached.
+    age: u64,
+    /// Seconds until the response expires, negative if it is stale.
+    expires_in: i64,
+}
+
+#[derive(Serialize)]
+struct Purged {
+    purged: usize,
+}
+
+fn json_response<T: Serialize>(value: &T) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(value).unwrap()))
+        .unwrap()
+}
+
+/// Returns the value of a query parameter.
+fn query_parameter(request: &Request<Body>, name: &str) -> Option<String> {
+    form_urlencoded::parse(request.uri().query()?.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Seconds from `from` to `to`, negative if `to` is earlier.
+fn seconds_between(from: SystemTime, to: SystemTime) -> i64 {
+    match to.duration_since(from) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+/// Handles requests to `/admin/...`, which are only available to clients
+/// configured with `admin = true`.
+pub async fn handle(
+    state: Arc<State>,
+    request: Request<Body>,
+) -> Result<Response<Body>, RequestError> {
+    let client = auth::authenticate_admin(request.headers())?;
+    let source = format!("client {:?}", client.name);
+
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/admin/read-only") => {}
+        (&Method::PUT, "/admin/read-only") => state.read_only.set(true, &source),
+        (&Method::DELETE, "/admin/read-only") => state.read_only.set(false, &source),
+        (&Method::GET, "/admin/cache") => {
+            let usage = state.cache.usage().await;
+
+            return Ok(json_response(&CacheStatus {
+                store: state.cache.store_kind(),
+                entries: usage.entries.into_iter().collect(),
+                bytes: usage.bytes,
+            }));
+        }
+        (&Method::DELETE, "/admin/cache") => {
+            let purged = state.cache.flush().await;
+            info!("Flushed {} cache entries for {}", purged, source);
+
+            return Ok(json_response(&Purged { purged }));
+        }
+        (&Method::GET, "/admin/cache/entries") => {
+            let key = query_parameter(&request, "key")
+                .ok_or(RequestError::MissingParameter { name: "key" })?;
+            let entry = state
+                .cache
+                .entry(&key)
+                .await
+                .ok_or(RequestError::UnknownCacheKey)?;
+
+            let now = SystemTime::now();
+            let age = now
+                .duration_since(entry.info.stored_at)
+                .unwrap_or(Duration::ZERO);
+
+            return Ok(json_response(&CacheEntry {
+                size: entry.size(&key),
+                key,
+                route: entry.info.route,
+                path: entry.info.path,
+                status: entry.statuscode.as_u16(),
+                age: age.as_secs(),
+                expires_in: seconds_between(now, entry.info.expires_at),
+            }));
+        }
+        (&Method::DELETE, "/admin/cache/entries") => {
+            let purged = match (
+                query_parameter(&request, "key"),
+                query_parameter(&request, "prefix"),
+            ) {
+                (Some(key), _) => state.cache.purge(&key).await,
+                (None, Some(prefix)) => state.cache.purge_prefix(&prefix).await,
+                (None, None) => return Err(RequestError::MissingParameter { name: "key" }),
+            };
+            info!("Purged {} cache entries for {}", purged, source);
+
+            return Ok(json_response(&Purged { purged }));
+        }
+        _ => return Err(RequestError::NotFound),
+    }
+
+    Ok(json_response(&ReadOnlyStatus {
+        read_only: state.read_only.is_enabled(),
+    }))
+}
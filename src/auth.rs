@@ -0,0 +1,117 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{header::PROXY_AUTHORIZATION, HeaderMap};
+
+use crate::{
+    config::{self, ClientKeyConfig},
+    error::RequestError,
+    ratelimiter_map::normalize_token,
+};
+
+/// Header clients can send their key in, as an alternative to HTTP Basic
+/// authentication in `Proxy-Authorization`.
+pub static PROXY_AUTH: &str = "x-proxy-auth";
+
+/// Name used in `tokens` to refer to the default token.
+static DEFAULT_TOKEN_NAME: &str = "default";
+
+/// Compares two keys in constant time, so that the comparison doesn't leak
+/// how much of a key was guessed correctly.
+fn keys_match(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Extracts the key from either `X-Proxy-Auth` or the password of HTTP Basic
+/// credentials in `Proxy-Authorization`.
+fn provided_key(headers: &HeaderMap) -> Option<Vec<u8>> {
+    if let Some(key) = headers.get(PROXY_AUTH) {
+        return Some(key.as_bytes().to_vec());
+    }
+
+    let encoded = headers
+        .get(PROXY_AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = STANDARD.decode(encoded.trim()).ok()?;
+    let split = decoded.iter().position(|byte| *byte == b':')?;
+
+    Some(decoded[split + 1..].to_vec())
+}
+
+/// Returns the client that sent the request, or `None` if authentication is
+/// not required.
+pub fn authenticate(headers: &HeaderMap) -> Result<Option<ClientKeyConfig>, RequestError> {
+    let config = config::current();
+
+    if !config.auth.required {
+        return Ok(None);
+    }
+
+    authenticate_client(headers).map(Some)
+}
+
+/// Returns the client that sent the request, whether authentication is
+/// required for proxied requests or not. Used for endpoints that were made
+/// private on their own.
+pub fn authenticate_client(headers: &HeaderMap) -> Result<ClientKeyConfig, RequestError> {
+    let key = provided_key(headers).ok_or(RequestError::ProxyAuthRequired)?;
+
+    config::current()
+        .auth
+        .clients
+        .iter()
+        .find(|client| keys_match(client.key.expose().as_bytes(), &key))
+        .cloned()
+        .ok_or(RequestError::ProxyAuthRequired)
+}
+
+/// Returns the client that sent the request to an admin endpoint. Admin
+/// endpoints always require a key, whether authentication is required for
+/// other requests or not.
+pub fn authenticate_admin(headers: &HeaderMap) -> Result<ClientKeyConfig, RequestError> {
+    let client = authenticate_client(headers)?;
+
+    if client.admin {
+        Ok(client)
+    } else {
+        Err(RequestError::Forbidden)
+    }
+}
+
+/// Whether `allowed`, which may be a token, an alias from the vault or
+/// `"default"`, refers to `token`.
+pub fn token_matches(allowed: &str, token: &str, is_default: bool) -> bool {
+    if allowed == DEFAULT_TOKEN_NAME {
+        return is_default;
+    }
+
+    let config = config::current();
+    let allowed = match config.vault.aliases.get(allowed) {
+        Some(aliased) => aliased.expose(),
+        None => allowed,
+    };
+
+    normalize_token(allowed.to_owned()) == normalize_token(token.to_owned())
+}
+
+/// Checks that the client may use the token it is about to make requests
+/// with.
+pub fn authorize_token(
+    client: Option<&ClientKeyConfig>,
+    token: &str,
+    is_default: bool,
+) -> Result<(), RequestError> {
+    let allowed = match client.and_then(|client| client.tokens.as_ref()) {
+        Some(allowed) => allowed,
+        None => return Ok(()),
+    };
+
+    if allowed
+        .iter()
+        .any(|allowed| token_matches(allowed.expose(), token, is_default))
+    {
+        Ok(())
+    } else {
+        Err(RequestError::TokenNotAllowed)
+    }
+}
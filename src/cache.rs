@@ -1,68 +1,444 @@
-use crate::parse_env;
-use ahash::AHashMap;
-use http::{HeaderMap, HeaderValue, StatusCode};
-use lazy_static::lazy_static;
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+mod disk;
+mod index;
+mod memory;
+mod redis;
+
+pub use self::{disk::DiskStore, memory::MemoryStore, redis::RedisStore};
+
+use self::index::Index;
+use crate::{
+    conditional,
+    config::{self, CacheConfig, CachePartition, CacheStoreKind},
+    ratelimiter_map::{application_id, hash_token},
+};
+use ahash::{AHashMap, RandomState};
+use http::{header::HeaderName, HeaderMap, HeaderValue, StatusCode};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
 use tokio::time::{interval, Instant};
+use tracing::{debug, error};
 
 #[cfg(feature = "expose-metrics")]
-use std::env;
+use lazy_static::lazy_static;
+#[cfg(feature = "expose-metrics")]
+use metrics::{counter, gauge};
 
 #[cfg(feature = "expose-metrics")]
-use metrics::{gauge, increment_gauge};
+lazy_static! {
+    static ref METRIC_KEY_ENTRIES: String =
+        format!("{}_cache_entries", config::current().metrics.key);
+}
 
 #[cfg(feature = "expose-metrics")]
 lazy_static! {
-    static ref METRIC_KEY_USERS: String = format!(
-        "{}_cache_users",
-        env::var("METRIC_KEY").unwrap_or_else(|_| "twilight_http_proxy".into())
-    );
+    static ref METRIC_KEY_BYTES: String = format!("{}_cache_bytes", config::current().metrics.key);
 }
 
 #[cfg(feature = "expose-metrics")]
 lazy_static! {
-    static ref METRIC_KEY_INVITES: String = format!(
-        "{}_cache_invites",
-        env::var("METRIC_KEY").unwrap_or_else(|_| "twilight_http_proxy".into())
-    );
+    static ref METRIC_KEY_EVICTIONS: String =
+        format!("{}_cache_evictions", config::current().metrics.key);
 }
+
+#[cfg(feature = "expose-metrics")]
 lazy_static! {
-    static ref CACHE_DURATION: u64 = parse_env("CACHE_DURATION").unwrap_or(60 * 10);
+    static ref METRIC_KEY_NEGATIVE_HITS: String =
+        format!("{}_cache_negative_hits", config::current().metrics.key);
+}
+
+/// How long a background revalidation may take before another one is
+/// started.
+const REVALIDATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Future returned by the methods of [`CacheStore`].
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Decides which responses are kept by [`CacheStore::retain`].
+pub type Filter<'a> = Box<dyn Fn(&str, &EntryInfo) -> bool + Send + Sync + 'a>;
+
+/// What is known about a cached response without reading all of it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EntryInfo {
+    /// Name of the `Path` variant the response belongs to.
+    pub route: String,
+    /// Path of the request without API version and query.
+    pub path: String,
+    pub stored_at: SystemTime,
+    pub expires_at: SystemTime,
+    /// Whether the response has validators to revalidate it with once it
+    /// expired.
+    pub revalidatable: bool,
+}
+
+impl EntryInfo {
+    /// When the response can be removed, because it may no longer be served
+    /// stale or revalidated.
+    fn removable_at(&self, config: &CacheConfig) -> SystemTime {
+        let mut retention = config.stale_while_revalidate.max(config.stale_if_error);
+
+        if self.revalidatable {
+            retention = retention.max(config.revalidation_window);
+        }
+
+        self.expires_at + Duration::from_secs(retention)
+    }
 }
 
+impl AsRef<EntryInfo> for EntryInfo {
+    fn as_ref(&self) -> &EntryInfo {
+        self
+    }
+}
+
+#[derive(Clone)]
 pub struct CachedResponse {
-    cached_at: Instant,
-    bytes: Vec<u8>,
-    headers: HeaderMap<HeaderValue>,
-    statuscode: StatusCode,
+    pub info: EntryInfo,
+    pub bytes: Vec<u8>,
+    pub headers: HeaderMap<HeaderValue>,
+    pub statuscode: StatusCode,
+}
+
+impl AsRef<EntryInfo> for CachedResponse {
+    fn as_ref(&self) -> &EntryInfo {
+        &self.info
+    }
+}
+
+/// Representation of a cached response in stores outside of memory.
+#[derive(Deserialize, Serialize)]
+struct StoredResponse {
+    key: String,
+    route: String,
+    path: String,
+    stored_at: SystemTime,
+    expires_at: SystemTime,
+    revalidatable: bool,
+    status: u16,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Vec<u8>,
 }
 
 impl CachedResponse {
     pub fn new(
+        route: String,
+        path: String,
+        ttl: Duration,
         bytes: Vec<u8>,
         headers: HeaderMap<HeaderValue>,
         statuscode: StatusCode,
     ) -> CachedResponse {
+        let now = SystemTime::now();
+
         CachedResponse {
-            cached_at: Instant::now(),
+            info: EntryInfo {
+                route,
+                path,
+                stored_at: now,
+                expires_at: now + ttl,
+                revalidatable: conditional::has_validators(&headers),
+            },
             bytes,
             headers,
             statuscode,
         }
     }
+
+    /// Approximate amount of memory the response takes up.
+    pub fn size(&self, key: &str) -> usize {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+
+        key.len() + self.info.route.len() + self.info.path.len() + self.bytes.len() + headers
+    }
+
+    /// Serializes the response together with its key.
+    pub fn to_bytes(&self, key: &str) -> Vec<u8> {
+        let stored = StoredResponse {
+            key: key.to_owned(),
+            route: self.info.route.clone(),
+            path: self.info.path.clone(),
+            stored_at: self.info.stored_at,
+            expires_at: self.info.expires_at,
+            revalidatable: self.info.revalidatable,
+            status: self.statuscode.as_u16(),
+            headers: self
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_str().to_owned(), value.as_bytes().to_vec()))
+                .collect(),
+            body: self.bytes.clone(),
+        };
+
+        bincode::serialize(&stored).expect("cached responses are serializable")
+    }
+
+    /// Deserializes a response and its key, returning `None` if the data is
+    /// corrupt.
+    pub fn from_bytes(bytes: &[u8]) -> Option<(String, CachedResponse)> {
+        let stored: StoredResponse = bincode::deserialize(bytes).ok()?;
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in stored.headers {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).ok()?,
+                HeaderValue::from_bytes(&value).ok()?,
+            );
+        }
+
+        let response = CachedResponse {
+            info: EntryInfo {
+                route: stored.route,
+                path: stored.path,
+                stored_at: stored.stored_at,
+                expires_at: stored.expires_at,
+                revalidatable: stored.revalidatable,
+            },
+            bytes: stored.body,
+            headers,
+            statuscode: StatusCode::from_u16(stored.status).ok()?,
+        };
+
+        Some((stored.key, response))
+    }
+}
+
+/// Amount of cached responses per route, and their total size.
+#[derive(Debug, Default)]
+pub struct Usage {
+    pub entries: AHashMap<String, usize>,
+    pub bytes: usize,
+}
+
+/// Storage for cached responses.
+///
+/// Stores evict the least recently used responses once they hold more than
+/// `cache.max_bytes`, but leave removing expired ones to the reaper.
+pub trait CacheStore: Send + Sync {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CachedResponse>>;
+
+    fn insert(&self, key: String, value: CachedResponse) -> StoreFuture<'_, ()>;
+
+    /// Removes all responses for which `f` returns `false`, returning how
+    /// many were removed.
+    fn retain<'a>(&'a self, f: Filter<'a>) -> StoreFuture<'a, usize>;
+
+    /// Removes the responses for `path`, its parents and children, and all
+    /// responses of `routes`, returning how many were removed.
+    fn invalidate<'a>(&'a self, path: &'a str, routes: &'a [String]) -> StoreFuture<'a, usize>;
+
+    fn usage(&self) -> StoreFuture<'_, Usage>;
+}
+
+/// Entries in least recently used order, evicting the least recently used
+/// ones once they take up more than `cache.max_bytes`.
+struct Lru<V> {
+    lru: LruCache<String, (V, usize), RandomState>,
+    index: Index,
+    bytes: usize,
 }
 
+impl<V: AsRef<EntryInfo>> Lru<V> {
+    fn new() -> Self {
+        Self {
+            lru: LruCache::unbounded_with_hasher(RandomState::new()),
+            index: Index::default(),
+            bytes: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<&V> {
+        self.lru.get(key).map(|(value, _)| value)
+    }
+
+    /// Inserts the entry, returning the entries it replaced or evicted.
+    fn insert(&mut self, key: String, value: V, size: usize) -> Vec<(String, V)> {
+        let max_bytes = config::current().cache.max_bytes;
+
+        if size as u64 > max_bytes {
+            debug!("Not caching {}, it is larger than the cache", key);
+
+            // The previous response is outdated either way
+            let mut removed: Vec<(String, V)> = self
+                .remove(&key)
+                .map(|value| (key.clone(), value))
+                .into_iter()
+                .collect();
+            removed.push((key, value));
+
+            return removed;
+        }
+
+        let mut removed: Vec<(String, V)> = self
+            .remove(&key)
+            .map(|value| (key.clone(), value))
+            .into_iter()
+            .collect();
+
+        self.bytes += size;
+        self.index.insert(&key, value.as_ref());
+        self.lru.put(key, (value, size));
+
+        let mut evicted = 0;
+        while self.bytes as u64 > max_bytes {
+            let (key, (value, size)) = match self.lru.pop_lru() {
+                Some(entry) => entry,
+                None => break,
+            };
+
+            self.bytes -= size;
+            self.index.remove(&key, value.as_ref());
+            removed.push((key, value));
+            evicted += 1;
+        }
+
+        if evicted > 0 {
+            debug!("Evicted {} cache entries to stay within budget", evicted);
+        }
+
+        #[cfg(feature = "expose-metrics")]
+        {
+            counter!(METRIC_KEY_EVICTIONS.as_str(), evicted);
+            gauge!(METRIC_KEY_BYTES.as_str(), self.bytes as f64);
+        }
+
+        removed
+    }
+
+    fn remove(&mut self, key: &str) -> Option<V> {
+        let (value, size) = self.lru.pop(key)?;
+        self.bytes -= size;
+        self.index.remove(key, value.as_ref());
+
+        Some(value)
+    }
+
+    /// Removes all entries for which `f` returns `false`, returning them.
+    fn retain(&mut self, mut f: impl FnMut(&str, &V) -> bool) -> Vec<(String, V)> {
+        let keys: Vec<String> = self
+            .lru
+            .iter()
+            .filter(|(key, (value, _))| !f(key, value))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        keys.into_iter()
+            .filter_map(|key| self.remove(&key).map(|value| (key, value)))
+            .collect()
+    }
+
+    /// Removes the entries for `path`, its parents and children, and all
+    /// entries of `routes`, returning them.
+    fn invalidate(&mut self, path: &str, routes: &[String]) -> Vec<(String, V)> {
+        self.index
+            .related(path, routes)
+            .into_iter()
+            .filter_map(|key| self.remove(&key).map(|value| (key, value)))
+            .collect()
+    }
+
+    fn usage(&self) -> Usage {
+        let mut usage = Usage {
+            bytes: self.bytes,
+            ..Usage::default()
+        };
+
+        for (_, (value, _)) in self.lru.iter() {
+            *usage
+                .entries
+                .entry(value.as_ref().route.clone())
+                .or_default() += 1;
+        }
+
+        usage
+    }
+}
+
+/// Returns the key of the response to a request, which separates responses
+/// for different tokens or applications if the route is partitioned.
+///
+/// The application ID of a token is only used if the token is `verified`, as
+/// anyone can make up a token with the ID of another application.
+pub fn key(
+    partition: CachePartition,
+    token: &str,
+    verified: bool,
+    api_route: &str,
+    query: Option<&str>,
+) -> String {
+    let application = application_id(token).filter(|_| verified);
+
+    let prefix = match (partition, application) {
+        (CachePartition::Shared, _) => String::new(),
+        (CachePartition::Application, Some(id)) => format!("application:{}:", id),
+        // Tokens that don't belong to a verified application are partitioned
+        // by token
+        (CachePartition::Application, None) | (CachePartition::Token, _) => {
+            format!("token:{}:", hash_token(token))
+        }
+    };
+
+    match query {
+        Some(query) => format!("{}{}?{}", prefix, api_route, query),
+        None => format!("{}{}", prefix, api_route),
+    }
+}
+
+/// Returns the API route and query of a key, without the partition.
+fn key_route(key: &str) -> &str {
+    // Partition prefixes never contain slashes, but routes start with one
+    key.find('/').map_or(key, |index| &key[index..])
+}
+
+/// Responses to GET requests, keyed by their API route and query.
 pub struct Cache {
-    users: RwLock<AHashMap<String, CachedResponse>>,
-    invites: RwLock<AHashMap<String, CachedResponse>>,
+    store: Box<dyn CacheStore>,
+    /// The store that is actually used, which is memory if the configured one
+    /// failed to open.
+    store_kind: CacheStoreKind,
+    /// When background revalidations of stale responses were started.
+    revalidating: Mutex<AHashMap<String, Instant>>,
 }
 
 impl Cache {
-    pub fn new() -> Arc<Cache> {
+    pub async fn new() -> Arc<Cache> {
+        let config = config::current();
+
+        let memory = || -> (Box<dyn CacheStore>, _) {
+            (Box::new(MemoryStore::new()), CacheStoreKind::Memory)
+        };
+
+        let (store, store_kind) = match (config.cache.store, &config.cache.directory) {
+            (CacheStoreKind::Redis, _) => (
+                Box::new(RedisStore::connect(&config.redis)) as Box<dyn CacheStore>,
+                CacheStoreKind::Redis,
+            ),
+            (CacheStoreKind::Disk, Some(directory)) => match DiskStore::open(directory) {
+                Ok(store) => (Box::new(store) as Box<dyn CacheStore>, CacheStoreKind::Disk),
+                Err(e) => {
+                    error!(
+                        "Failed to open cache directory {}, caching in memory instead: {}",
+                        directory.display(),
+                        e
+                    );
+                    memory()
+                }
+            },
+            _ => memory(),
+        };
+
         let c = Arc::new(Cache {
-            users: Default::default(),
-            invites: Default::default(),
+            store,
+            store_kind,
+            revalidating: Mutex::default(),
         });
 
         tokio::spawn(reaper(c.clone()));
@@ -70,36 +446,111 @@ impl Cache {
         c
     }
 
-    pub fn insert_user(
-        &self,
-        key: String,
-        value: Vec<u8>,
-        headers: HeaderMap<HeaderValue>,
-        statuscode: StatusCode,
-    ) {
-        #[cfg(feature = "expose-metrics")]
-        increment_gauge!(METRIC_KEY_USERS.as_str(), 1f64);
-        insert(&self.users, key, value, headers, statuscode)
+    pub async fn insert(&self, key: String, value: CachedResponse) {
+        self.revalidating
+            .lock()
+            .expect("Cache got poisoned")
+            .remove(&key);
+        self.store.insert(key, value).await;
     }
 
-    pub fn insert_invite(
-        &self,
-        key: String,
-        value: Vec<u8>,
-        headers: HeaderMap<HeaderValue>,
-        statuscode: StatusCode,
-    ) {
-        #[cfg(feature = "expose-metrics")]
-        increment_gauge!(METRIC_KEY_INVITES.as_str(), 1f64);
-        insert(&self.invites, key, value, headers, statuscode)
+    /// Evicts entries made stale by a successful mutation of `path`: those
+    /// for the path itself, its parents and children, and every entry of the
+    /// given routes.
+    pub async fn invalidate(&self, path: &str, routes: &[String]) {
+        let removed = self.store.invalidate(path, routes).await;
+
+        debug!(
+            "Invalidated {} cache entries after mutating {}",
+            removed, path
+        );
+    }
+
+    pub fn store_kind(&self) -> CacheStoreKind {
+        self.store_kind
     }
 
-    pub fn get_user(&self, key: &str) -> Option<(Vec<u8>, HeaderMap<HeaderValue>, StatusCode)> {
-        get(&self.users, key)
+    pub async fn usage(&self) -> Usage {
+        self.store.usage().await
     }
 
-    pub fn get_invite(&self, key: &str) -> Option<(Vec<u8>, HeaderMap<HeaderValue>, StatusCode)> {
-        get(&self.users, key)
+    /// Returns the response for the key, no matter whether it expired.
+    pub async fn entry(&self, key: &str) -> Option<CachedResponse> {
+        self.store.get(key).await
+    }
+
+    /// Returns the expired response for the key if it can be revalidated with
+    /// a conditional request.
+    pub async fn revalidatable(&self, key: &str) -> Option<CachedResponse> {
+        let cached = self.store.get(key).await?;
+        let now = SystemTime::now();
+
+        (cached.info.revalidatable
+            && cached.info.expires_at <= now
+            && cached.info.removable_at(&config::current().cache) > now)
+            .then_some(cached)
+    }
+
+    /// Removes the response for the key, returning how many were removed.
+    pub async fn purge(&self, key: &str) -> usize {
+        self.store
+            .retain(Box::new(move |other, _| other != key))
+            .await
+    }
+
+    /// Removes all responses whose API route starts with `prefix`, in every
+    /// partition, returning how many were removed.
+    pub async fn purge_prefix(&self, prefix: &str) -> usize {
+        self.store
+            .retain(Box::new(move |key, _| !key_route(key).starts_with(prefix)))
+            .await
+    }
+
+    /// Removes all responses, returning how many were removed.
+    pub async fn flush(&self) -> usize {
+        self.store.retain(Box::new(|_, _| false)).await
+    }
+
+    /// Marks a stale response as being revalidated, returning `false` if a
+    /// revalidation is already running.
+    pub fn start_revalidation(&self, key: &str) -> bool {
+        let mut revalidating = self.revalidating.lock().expect("Cache got poisoned");
+        let now = Instant::now();
+
+        if let Some(since) = revalidating.get(key) {
+            if now - *since < REVALIDATION_TIMEOUT {
+                return false;
+            }
+        }
+
+        revalidating.insert(key.to_owned(), now);
+
+        true
+    }
+
+    pub async fn get(&self, key: &str) -> Option<(Vec<u8>, HeaderMap<HeaderValue>, StatusCode)> {
+        self.get_stale(key, Duration::ZERO).await
+    }
+
+    /// Returns the response even if it expired, as long as that was no more
+    /// than `max_staleness` ago.
+    pub async fn get_stale(
+        &self,
+        key: &str,
+        max_staleness: Duration,
+    ) -> Option<(Vec<u8>, HeaderMap<HeaderValue>, StatusCode)> {
+        let cached = self.store.get(key).await?;
+
+        if SystemTime::now() >= cached.info.expires_at + max_staleness {
+            return None;
+        }
+
+        #[cfg(feature = "expose-metrics")]
+        if cached.statuscode == StatusCode::NOT_FOUND {
+            counter!(METRIC_KEY_NEGATIVE_HITS.as_str(), 1, "route" => cached.info.route.clone());
+        }
+
+        Some((cached.bytes, cached.headers, cached.statuscode))
     }
 }
 
@@ -107,54 +558,48 @@ pub async fn reaper(cache: Arc<Cache>) {
     let mut interval = interval(Duration::from_secs(120));
     loop {
         interval.tick().await;
-        clean_cache(&cache.invites);
-        clean_cache(&cache.users);
 
-        #[cfg(feature = "expose-metrics")]
-        {
-            gauge!(
-                METRIC_KEY_INVITES.as_str(),
-                cache.invites.read().expect("Cache got poisoned").len() as f64
-            );
-            gauge!(
-                METRIC_KEY_USERS.as_str(),
-                cache.users.read().expect("Cache got poisoned").len() as f64
-            );
+        // Keep expired responses for as long as they may still be served or
+        // revalidated
+        let config = config::current();
+        let now = SystemTime::now();
+        cache
+            .store
+            .retain(Box::new(|_, info| info.removable_at(&config.cache) > now))
+            .await;
+
+        cache
+            .revalidating
+            .lock()
+            .expect("Cache got poisoned")
+            .retain(|_, since| since.elapsed() < REVALIDATION_TIMEOUT);
+
+        // Routes without entries are reported too, so that their gauges drop
+        // to 0
+        let usage = cache.store.usage().await;
+        let mut counts: AHashMap<&str, usize> = config
+            .cache
+            .cached_routes()
+            .into_iter()
+            .map(|route| (route, 0))
+            .collect();
+
+        for (route, count) in &usage.entries {
+            counts.insert(route, *count);
         }
-    }
-}
 
-fn clean_cache(cache: &RwLock<AHashMap<String, CachedResponse>>) {
-    let now = Instant::now();
-    let mut cache = cache.write().expect("Cache got poisoned");
-    cache.retain(|_, value| (now - value.cached_at).as_secs() < *CACHE_DURATION);
-}
+        debug!(
+            "Done reaping expired cache entries, left: {:?} ({} bytes)",
+            counts, usage.bytes
+        );
 
-pub fn get(
-    cache: &RwLock<AHashMap<String, CachedResponse>>,
-    key: &str,
-) -> Option<(Vec<u8>, HeaderMap<HeaderValue>, StatusCode)> {
-    if let Some(cached) = cache.read().expect("cache got poisoned").get(key) {
-        if (Instant::now() - cached.cached_at).as_secs() < *CACHE_DURATION {
-            return Some((
-                cached.bytes.clone(),
-                cached.headers.clone(),
-                cached.statuscode,
-            ));
+        #[cfg(feature = "expose-metrics")]
+        {
+            for (route, count) in counts {
+                gauge!(METRIC_KEY_ENTRIES.as_str(), count as f64, "route" => route.to_owned());
+            }
+
+            gauge!(METRIC_KEY_BYTES.as_str(), usage.bytes as f64);
         }
     }
-    None
-}
-
-pub fn insert(
-    cache: &RwLock<AHashMap<String, CachedResponse>>,
-    key: String,
-    value: Vec<u8>,
-    headers: HeaderMap<HeaderValue>,
-    statuscode: StatusCode,
-) {
-    cache
-        .write()
-        .expect("Cache got poisoned")
-        .insert(key, CachedResponse::new(value, headers, statuscode));
 }
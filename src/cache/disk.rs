@@ -0,0 +1,235 @@
+use super::{CacheStore, CachedResponse, EntryInfo, Filter, Lru, StoreFuture, Usage};
+use crate::config;
+use sha2::{Digest, Sha256};
+use std::{
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+use tracing::{debug, info, warn};
+
+/// Keeps cached responses in files of a directory, so that they survive
+/// restarts. Only an index of the responses is kept in memory.
+pub struct DiskStore {
+    directory: PathBuf,
+    index: Mutex<Lru<EntryInfo>>,
+    /// Used to give every file being written a unique temporary name.
+    writes: AtomicU64,
+}
+
+impl DiskStore {
+    /// Opens the directory, creating it if needed, and indexes the responses
+    /// stored in it. Files that can't be read are deleted.
+    pub fn open(directory: &Path) -> io::Result<Self> {
+        fs::create_dir_all(directory)?;
+
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+
+            if path.extension() == Some(OsStr::new("tmp")) {
+                remove_file(&path);
+                continue;
+            }
+
+            let entry = fs::read(&path)
+                .ok()
+                .and_then(|bytes| CachedResponse::from_bytes(&bytes));
+
+            match entry {
+                Some((key, value)) if path == file_path(directory, &key) => {
+                    entries.push((key, value))
+                }
+                _ => {
+                    warn!("Deleting unreadable cache file {}", path.display());
+                    remove_file(&path);
+                }
+            }
+        }
+
+        // Responses expiring first are the first to be evicted
+        entries.sort_by_key(|(_, value)| value.info.expires_at);
+
+        let store = Self {
+            directory: directory.to_owned(),
+            index: Mutex::new(Lru::new()),
+            writes: AtomicU64::new(0),
+        };
+
+        let count = entries.len();
+        for (key, value) in entries {
+            let size = value.size(&key);
+            let removed = store.index().insert(key, value.info, size);
+
+            for (key, _) in removed {
+                remove_file(&store.file_path(&key));
+            }
+        }
+
+        info!(
+            "Loaded {} cached responses from {}",
+            count,
+            directory.display()
+        );
+
+        Ok(store)
+    }
+
+    fn index(&self) -> MutexGuard<'_, Lru<EntryInfo>> {
+        self.index.lock().expect("Cache got poisoned")
+    }
+
+    fn file_path(&self, key: &str) -> PathBuf {
+        file_path(&self.directory, key)
+    }
+
+    async fn remove_files(&self, keys: Vec<String>) {
+        for key in keys {
+            let path = self.file_path(&key);
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!("Failed to delete cache file {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+
+    async fn read(&self, key: &str) -> Option<CachedResponse> {
+        self.index().get(key)?;
+
+        let read = tokio::fs::read(self.file_path(key))
+            .await
+            .ok()
+            .and_then(|bytes| CachedResponse::from_bytes(&bytes));
+
+        match read {
+            Some((stored_key, value)) if stored_key == key => Some(value),
+            _ => {
+                debug!("Cache file for {} is gone or unreadable", key);
+                self.index().remove(key);
+                self.remove_files(vec![key.to_owned()]).await;
+
+                None
+            }
+        }
+    }
+
+    async fn write(&self, key: String, value: CachedResponse) {
+        let size = value.size(&key);
+        let path = self.file_path(&key);
+
+        // The index refuses responses larger than the cache, which are not
+        // worth writing
+        if size as u64 > config::current().cache.max_bytes {
+            let removed = self.index().insert(key, value.info, size);
+            self.remove_files(removed.into_iter().map(|(key, _)| key).collect())
+                .await;
+
+            return;
+        }
+
+        let temporary = path.with_extension(format!(
+            "{}.tmp",
+            self.writes.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        // Renaming is atomic, so that readers never see partially written
+        // files
+        let written = async {
+            tokio::fs::write(&temporary, value.to_bytes(&key)).await?;
+            tokio::fs::rename(&temporary, &path).await
+        }
+        .await;
+
+        if let Err(e) = written {
+            warn!("Failed to write cache file {}: {}", path.display(), e);
+            let _ = tokio::fs::remove_file(&temporary).await;
+
+            return;
+        }
+
+        let removed = {
+            let mut index = self.index();
+            let removed = index.insert(key.clone(), value.info, size);
+
+            // A replaced entry shares its file with the new one
+            let stored = index.get(&key).is_some();
+            removed
+                .into_iter()
+                .map(|(removed, _)| removed)
+                .filter(|removed| *removed != key || !stored)
+                .collect()
+        };
+
+        self.remove_files(removed).await;
+    }
+}
+
+impl CacheStore for DiskStore {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CachedResponse>> {
+        Box::pin(self.read(key))
+    }
+
+    fn insert(&self, key: String, value: CachedResponse) -> StoreFuture<'_, ()> {
+        Box::pin(self.write(key, value))
+    }
+
+    fn retain<'a>(&'a self, f: Filter<'a>) -> StoreFuture<'a, usize> {
+        Box::pin(async move {
+            let removed: Vec<String> = self
+                .index()
+                .retain(|key, info| f(key, info))
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect();
+            let count = removed.len();
+
+            self.remove_files(removed).await;
+
+            count
+        })
+    }
+
+    fn invalidate<'a>(&'a self, path: &'a str, routes: &'a [String]) -> StoreFuture<'a, usize> {
+        Box::pin(async move {
+            let removed: Vec<String> = self
+                .index()
+                .invalidate(path, routes)
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect();
+            let count = removed.len();
+
+            self.remove_files(removed).await;
+
+            count
+        })
+    }
+
+    fn usage(&self) -> StoreFuture<'_, Usage> {
+        let usage = self.index().usage();
+
+        Box::pin(async move { usage })
+    }
+}
+
+/// Returns the path of the file a response is stored in. Keys are hashed so
+/// that they are valid file names.
+fn file_path(directory: &Path, key: &str) -> PathBuf {
+    let name: String = Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    directory.join(name)
+}
+
+fn remove_file(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        warn!("Failed to delete cache file {}: {}", path.display(), e);
+    }
+}
@@ -0,0 +1,177 @@
+use super::EntryInfo;
+use ahash::{AHashMap, AHashSet};
+
+type Keys = AHashMap<String, AHashSet<String>>;
+
+/// Keys of cached responses by route and path, so that the responses made
+/// stale by a mutation can be looked up instead of going through all of them.
+#[derive(Default)]
+pub struct Index {
+    routes: Keys,
+    /// Keys by the path of their response.
+    paths: Keys,
+    /// Keys by every parent of the path of their response.
+    children: Keys,
+}
+
+impl Index {
+    pub fn insert(&mut self, key: &str, info: &EntryInfo) {
+        add(&mut self.routes, &info.route, key);
+        add(&mut self.paths, &info.path, key);
+
+        for parent in parents(&info.path) {
+            add(&mut self.children, parent, key);
+        }
+    }
+
+    pub fn remove(&mut self, key: &str, info: &EntryInfo) {
+        remove(&mut self.routes, &info.route, key);
+        remove(&mut self.paths, &info.path, key);
+
+        for parent in parents(&info.path) {
+            remove(&mut self.children, parent, key);
+        }
+    }
+
+    /// Returns the keys of responses for `path`, its parents and children,
+    /// and of all responses of `routes`.
+    pub fn related(&self, path: &str, routes: &[String]) -> AHashSet<String> {
+        parents(path)
+            .chain(Some(path))
+            .filter_map(|path| self.paths.get(path))
+            .chain(self.children.get(path))
+            .chain(routes.iter().filter_map(|route| self.routes.get(route)))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+fn add(keys: &mut Keys, name: &str, key: &str) {
+    keys.entry(name.to_owned())
+        .or_default()
+        .insert(key.to_owned());
+}
+
+fn remove(keys: &mut Keys, name: &str, key: &str) {
+    if let Some(set) = keys.get_mut(name) {
+        set.remove(key);
+
+        if set.is_empty() {
+            keys.remove(name);
+        }
+    }
+}
+
+/// Returns the parents of a path, such as `/channels` and `/channels/1` for
+/// `/channels/1/messages`.
+pub fn parents(path: &str) -> impl Iterator<Item = &str> {
+    path.match_indices('/')
+        .map(|(index, _)| index)
+        .filter(|index| *index > 0)
+        .map(move |index| &path[..index])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parents, EntryInfo, Index};
+    use std::time::SystemTime;
+
+    fn info(route: &str, path: &str) -> EntryInfo {
+        EntryInfo {
+            route: route.to_owned(),
+            path: path.to_owned(),
+            stored_at: SystemTime::now(),
+            expires_at: SystemTime::now(),
+            revalidatable: false,
+        }
+    }
+
+    /// Indexes the responses under the key the proxy would use.
+    fn index(entries: &[(&str, &str)]) -> Index {
+        let mut index = Index::default();
+        for (route, path) in entries {
+            index.insert(&format!("/api/v10{}", path), &info(route, path));
+        }
+
+        index
+    }
+
+    fn related(index: &Index, path: &str, routes: &[&str]) -> Vec<String> {
+        let routes: Vec<String> = routes.iter().map(|route| (*route).to_owned()).collect();
+        let mut keys: Vec<String> = index.related(path, &routes).into_iter().collect();
+        keys.sort();
+
+        keys
+    }
+
+    #[test]
+    fn parents_exclude_path_itself() {
+        let all: Vec<&str> = parents("/guilds/1/roles/2").collect();
+
+        assert_eq!(all, ["/guilds", "/guilds/1", "/guilds/1/roles"]);
+        assert_eq!(parents("/guilds").count(), 0);
+    }
+
+    #[test]
+    fn mutation_evicts_parents() {
+        let index = index(&[
+            ("GuildsIdRoles", "/guilds/1/roles"),
+            ("GuildsIdRoles", "/guilds/2/roles"),
+            ("GuildsIdRolesId", "/guilds/1/roles/3"),
+        ]);
+
+        assert_eq!(
+            related(&index, "/guilds/1/roles/2", &[]),
+            ["/api/v10/guilds/1/roles"]
+        );
+    }
+
+    #[test]
+    fn mutation_evicts_path_and_children() {
+        let index = index(&[
+            ("GuildsId", "/guilds/1"),
+            ("GuildsIdRoles", "/guilds/1/roles"),
+            ("GuildsIdRolesId", "/guilds/1/roles/2"),
+            ("GuildsIdRoles", "/guilds/12/roles"),
+            ("GuildsId", "/guilds/2"),
+        ]);
+
+        assert_eq!(
+            related(&index, "/guilds/1", &[]),
+            [
+                "/api/v10/guilds/1",
+                "/api/v10/guilds/1/roles",
+                "/api/v10/guilds/1/roles/2"
+            ]
+        );
+    }
+
+    #[test]
+    fn mutation_evicts_routes() {
+        let index = index(&[
+            ("GuildsIdChannels", "/guilds/1/channels"),
+            ("GuildsIdChannels", "/guilds/2/channels"),
+            ("ChannelsIdMessages", "/channels/5/messages"),
+        ]);
+
+        assert_eq!(
+            related(&index, "/channels/4", &["GuildsIdChannels"]),
+            ["/api/v10/guilds/1/channels", "/api/v10/guilds/2/channels"]
+        );
+    }
+
+    #[test]
+    fn removed_entries_are_not_related() {
+        let mut index = index(&[("GuildsIdRoles", "/guilds/1/roles")]);
+        index.remove(
+            "/api/v10/guilds/1/roles",
+            &info("GuildsIdRoles", "/guilds/1/roles"),
+        );
+
+        assert!(related(&index, "/guilds/1", &["GuildsIdRoles"]).is_empty());
+        assert!(index.routes.is_empty());
+        assert!(index.paths.is_empty());
+        assert!(index.children.is_empty());
+    }
+}
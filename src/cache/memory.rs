@@ -0,0 +1,51 @@
+use super::{CacheStore, CachedResponse, Filter, Lru, StoreFuture, Usage};
+use std::{
+    future::ready,
+    sync::{Mutex, MutexGuard},
+};
+
+/// Keeps cached responses in memory, losing them on restart.
+pub struct MemoryStore {
+    entries: Mutex<Lru<CachedResponse>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(Lru::new()),
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, Lru<CachedResponse>> {
+        self.entries.lock().expect("Cache got poisoned")
+    }
+}
+
+impl CacheStore for MemoryStore {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CachedResponse>> {
+        Box::pin(ready(self.entries().get(key).cloned()))
+    }
+
+    fn insert(&self, key: String, value: CachedResponse) -> StoreFuture<'_, ()> {
+        let size = value.size(&key);
+        self.entries().insert(key, value, size);
+
+        Box::pin(ready(()))
+    }
+
+    fn retain<'a>(&'a self, f: Filter<'a>) -> StoreFuture<'a, usize> {
+        let removed = self.entries().retain(|key, value| f(key, &value.info));
+
+        Box::pin(ready(removed.len()))
+    }
+
+    fn invalidate<'a>(&'a self, path: &'a str, routes: &'a [String]) -> StoreFuture<'a, usize> {
+        let removed = self.entries().invalidate(path, routes);
+
+        Box::pin(ready(removed.len()))
+    }
+
+    fn usage(&self) -> StoreFuture<'_, Usage> {
+        Box::pin(ready(self.entries().usage()))
+    }
+}
@@ -0,0 +1,487 @@
+use super::{
+    index::parents, CacheStore, CachedResponse, EntryInfo, Filter, MemoryStore, StoreFuture, Usage,
+};
+use crate::{
+    config::{self, RedisConfig},
+    redis_connection::RedisConnection,
+};
+use ::redis::{aio::ConnectionManager, AsyncCommands, RedisResult};
+use std::{collections::HashMap, time::SystemTime};
+
+/// Keeps cached responses on a Redis-compatible server, so that they are
+/// shared between proxy replicas. Responses expire on the server by
+/// themselves, and an index of them is kept in a hash for invalidation.
+///
+/// While the server is unreachable, responses are cached in memory instead.
+pub struct RedisStore {
+    connection: RedisConnection,
+    /// Prepended to the keys of all entries.
+    prefix: String,
+    fallback: MemoryStore,
+}
+
+impl RedisStore {
+    pub fn connect(config: &RedisConfig) -> Self {
+        Self {
+            connection: RedisConnection::connect(config, "caching"),
+            prefix: format!("{}cache:", config.key_prefix),
+            fallback: MemoryStore::new(),
+        }
+    }
+
+    fn entry_key(&self, key: &str) -> String {
+        format!("{}entry:{}", self.prefix, key)
+    }
+
+    fn index_key(&self) -> String {
+        format!("{}index", self.prefix)
+    }
+
+    /// Key of the set of entries of a route.
+    fn route_key(&self, route: &str) -> String {
+        format!("{}route:{}", self.prefix, route)
+    }
+
+    /// Key of the set of entries for a path.
+    fn path_key(&self, path: &str) -> String {
+        format!("{}path:{}", self.prefix, path)
+    }
+
+    /// Key of the set of entries for the children of a path.
+    fn children_key(&self, path: &str) -> String {
+        format!("{}children:{}", self.prefix, path)
+    }
+
+    /// Returns the keys of the sets an entry is kept in, so that it can be
+    /// found when its route or path is invalidated.
+    fn set_keys(&self, info: &EntryInfo) -> Vec<String> {
+        let mut keys = vec![self.route_key(&info.route), self.path_key(&info.path)];
+        keys.extend(parents(&info.path).map(|parent| self.children_key(parent)));
+
+        keys
+    }
+
+    /// Returns the index of all entries, with their size.
+    async fn index(&self) -> Option<Vec<(String, EntryInfo, usize)>> {
+        let mut connection = self.connection.get()?;
+        let index: HashMap<String, Vec<u8>> = self
+            .connection
+            .check(connection.hgetall(self.index_key()).await)?;
+
+        let entries = index
+            .into_iter()
+            .map(|(key, bytes)| match bincode::deserialize(&bytes) {
+                Ok((info, size)) => (key, info, size),
+                // Unreadable entries are treated as expired long ago
+                Err(_) => {
+                    let info = EntryInfo {
+                        route: String::new(),
+                        path: String::new(),
+                        stored_at: SystemTime::UNIX_EPOCH,
+                        expires_at: SystemTime::UNIX_EPOCH,
+                        revalidatable: false,
+                    };
+
+                    (key, info, 0)
+                }
+            })
+            .collect();
+
+        Some(entries)
+    }
+
+    async fn read(&self, key: &str) -> Option<CachedResponse> {
+        let mut connection = match self.connection.get() {
+            Some(connection) => connection,
+            None => return self.fallback.get(key).await,
+        };
+        let result: RedisResult<Option<Vec<u8>>> = connection.get(self.entry_key(key)).await;
+
+        match self.connection.check(result) {
+            Some(bytes) => match CachedResponse::from_bytes(&bytes?) {
+                Some((stored_key, value)) if stored_key == key => Some(value),
+                _ => None,
+            },
+            None => self.fallback.get(key).await,
+        }
+    }
+
+    async fn write(&self, key: String, value: CachedResponse) {
+        // Expired responses are kept for as long as they may still be served
+        // or revalidated
+        let removable_at = value.info.removable_at(&config::current().cache);
+        let ttl = match removable_at.duration_since(SystemTime::now()) {
+            Ok(ttl) if ttl.as_millis() > 0 => ttl.as_millis() as u64,
+            _ => return,
+        };
+
+        let mut connection = match self.connection.get() {
+            Some(connection) => connection,
+            None => return self.fallback.insert(key, value).await,
+        };
+
+        let info = bincode::serialize(&(&value.info, value.size(&key)))
+            .expect("entry infos are serializable");
+
+        let mut pipe = ::redis::pipe();
+        pipe.atomic()
+            .set(self.entry_key(&key), value.to_bytes(&key))
+            .arg("PX")
+            .arg(ttl)
+            .ignore()
+            .hset(self.index_key(), &key, info)
+            .ignore();
+
+        for set in self.set_keys(&value.info) {
+            pipe.sadd(set, &key).ignore();
+        }
+
+        let result: RedisResult<()> = pipe.query_async(&mut connection).await;
+
+        if self.connection.check(result).is_none() {
+            self.fallback.insert(key, value).await;
+        }
+    }
+
+    /// Deletes entries together with their place in the index and sets,
+    /// returning whether that succeeded.
+    async fn delete(
+        &self,
+        connection: &mut ConnectionManager,
+        entries: &[(String, Option<EntryInfo>)],
+        sets: &[String],
+    ) -> bool {
+        let keys: Vec<&str> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        let entry_keys: Vec<String> = keys.iter().map(|key| self.entry_key(key)).collect();
+
+        let mut pipe = ::redis::pipe();
+        pipe.atomic()
+            .del(entry_keys)
+            .ignore()
+            .hdel(self.index_key(), &keys)
+            .ignore();
+
+        for (key, info) in entries {
+            for set in info.iter().flat_map(|info| self.set_keys(info)) {
+                pipe.srem(set, key).ignore();
+            }
+        }
+
+        for set in sets {
+            pipe.srem(set, &keys).ignore();
+        }
+
+        let result: RedisResult<()> = pipe.query_async(connection).await;
+
+        self.connection.check(result).is_some()
+    }
+
+    async fn remove(&self, f: Filter<'_>) -> usize {
+        let removed_fallback = self
+            .fallback
+            .retain(Box::new(|key, info| f(key, info)))
+            .await;
+
+        let index = match self.index().await {
+            Some(index) => index,
+            None => return removed_fallback,
+        };
+
+        let removed: Vec<(String, Option<EntryInfo>)> = index
+            .into_iter()
+            .filter(|(key, info, _)| !f(key, info))
+            .map(|(key, info, _)| (key, Some(info)))
+            .collect();
+
+        if removed.is_empty() {
+            return removed_fallback;
+        }
+
+        let mut connection = match self.connection.get() {
+            Some(connection) => connection,
+            None => return removed_fallback,
+        };
+
+        if self.delete(&mut connection, &removed, &[]).await {
+            removed.len() + removed_fallback
+        } else {
+            removed_fallback
+        }
+    }
+
+    async fn remove_related(&self, path: &str, routes: &[String]) -> usize {
+        let removed_fallback = self.fallback.invalidate(path, routes).await;
+
+        let mut connection = match self.connection.get() {
+            Some(connection) => connection,
+            None => return removed_fallback,
+        };
+
+        let mut sets: Vec<String> = parents(path)
+            .chain(Some(path))
+            .map(|path| self.path_key(path))
+            .collect();
+        sets.push(self.children_key(path));
+        sets.extend(routes.iter().map(|route| self.route_key(route)));
+
+        let keys: Vec<String> = match self.connection.check(connection.sunion(&sets).await) {
+            Some(keys) => keys,
+            None => return removed_fallback,
+        };
+
+        if keys.is_empty() {
+            return removed_fallback;
+        }
+
+        // The entries are also kept in the sets of their own route and
+        // parents, which are only known from their info
+        let infos: Vec<Option<Vec<u8>>> = match self.connection.check(
+            ::redis::cmd("HMGET")
+                .arg(self.index_key())
+                .arg(&keys)
+                .query_async(&mut connection)
+                .await,
+        ) {
+            Some(infos) => infos,
+            None => return removed_fallback,
+        };
+
+        let removed: Vec<(String, Option<EntryInfo>)> = keys
+            .into_iter()
+            .zip(infos)
+            .map(|(key, info)| {
+                let info = info
+                    .and_then(|bytes| bincode::deserialize::<(EntryInfo, usize)>(&bytes).ok())
+                    .map(|(info, _)| info);
+
+                (key, info)
+            })
+            .collect();
+
+        if self.delete(&mut connection, &removed, &sets).await {
+            removed.len() + removed_fallback
+        } else {
+            removed_fallback
+        }
+    }
+
+    async fn measure(&self) -> Usage {
+        let index = match self.index().await {
+            Some(index) => index,
+            None => return self.fallback.usage().await,
+        };
+
+        let mut usage = Usage::default();
+        for (_, info, size) in index {
+            *usage.entries.entry(info.route).or_default() += 1;
+            usage.bytes += size;
+        }
+
+        usage
+    }
+}
+
+impl CacheStore for RedisStore {
+    fn get<'a>(&'a self, key: &'a str) -> StoreFuture<'a, Option<CachedResponse>> {
+        Box::pin(self.read(key))
+    }
+
+    fn insert(&self, key: String, value: CachedResponse) -> StoreFuture<'_, ()> {
+        Box::pin(self.write(key, value))
+    }
+
+    fn retain<'a>(&'a self, f: Filter<'a>) -> StoreFuture<'a, usize> {
+        Box::pin(self.remove(f))
+    }
+
+    fn invalidate<'a>(&'a self, path: &'a str, routes: &'a [String]) -> StoreFuture<'a, usize> {
+        Box::pin(self.remove_related(path, routes))
+    }
+
+    fn usage(&self) -> StoreFuture<'_, Usage> {
+        Box::pin(self.measure())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! The store is tested against the Redis-compatible server at
+    //! `TEST_REDIS_URL` (defaults to `redis://127.0.0.1:6379`), such as a local
+    //! `redis-server`, with `cargo test -- --ignored`.
+
+    use super::RedisStore;
+    use crate::{cache::CachedResponse, config::RedisConfig};
+    use http::{HeaderMap, StatusCode};
+    use redis::AsyncCommands;
+    use std::{
+        env,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+    use tokio::time::sleep;
+
+    /// Connects to the server, using keys that no other test uses.
+    async fn store() -> RedisStore {
+        let url = env::var("TEST_REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".into());
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let config = RedisConfig {
+            url: Some(url.parse().unwrap()),
+            key_prefix: format!("http-proxy-test:{}:", id),
+            ..RedisConfig::default()
+        };
+
+        let store = RedisStore::connect(&config);
+        for _ in 0..100 {
+            if store.connection.get().is_some() {
+                return store;
+            }
+
+            sleep(Duration::from_millis(20)).await;
+        }
+
+        panic!("test server is unreachable");
+    }
+
+    /// Caches a response for `path` under the key the proxy would use.
+    async fn write(store: &RedisStore, route: &str, path: &str) -> String {
+        let key = format!("/api/v10{}", path);
+        let response = CachedResponse::new(
+            route.to_owned(),
+            path.to_owned(),
+            Duration::from_secs(60),
+            b"{}".to_vec(),
+            HeaderMap::new(),
+            StatusCode::OK,
+        );
+        store.write(key.clone(), response).await;
+
+        key
+    }
+
+    async fn indexed(store: &RedisStore) -> Vec<String> {
+        let mut keys: Vec<String> = store
+            .index()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(key, _, _)| key)
+            .collect();
+        keys.sort();
+
+        keys
+    }
+
+    async fn members(store: &RedisStore, set: String) -> Vec<String> {
+        let mut connection = store.connection.get().unwrap();
+        let mut members: Vec<String> = connection.smembers(set).await.unwrap();
+        members.sort();
+
+        members
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at TEST_REDIS_URL"]
+    async fn write_indexes_response() {
+        let store = store().await;
+        let key = write(&store, "GuildsIdRoles", "/guilds/1/roles").await;
+
+        let read = store.read(&key).await.expect("response is cached");
+        assert_eq!(read.bytes, b"{}");
+        assert_eq!(read.info.path, "/guilds/1/roles");
+
+        assert_eq!(indexed(&store).await, [key.as_str()]);
+        for set in [
+            store.route_key("GuildsIdRoles"),
+            store.path_key("/guilds/1/roles"),
+            store.children_key("/guilds"),
+            store.children_key("/guilds/1"),
+        ] {
+            assert_eq!(members(&store, set).await, [key.as_str()]);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at TEST_REDIS_URL"]
+    async fn remove_related_removes_path_and_parents() {
+        let store = store().await;
+        let guild = write(&store, "GuildsId", "/guilds/1").await;
+        let roles = write(&store, "GuildsIdRoles", "/guilds/1/roles").await;
+        let other = write(&store, "GuildsIdRoles", "/guilds/2/roles").await;
+
+        assert_eq!(store.remove_related("/guilds/1/roles/3", &[]).await, 2);
+
+        assert!(store.read(&guild).await.is_none());
+        assert!(store.read(&roles).await.is_none());
+        assert!(store.read(&other).await.is_some());
+
+        assert_eq!(indexed(&store).await, [other.as_str()]);
+        assert_eq!(
+            members(&store, store.route_key("GuildsIdRoles")).await,
+            [other.as_str()]
+        );
+        assert_eq!(
+            members(&store, store.children_key("/guilds")).await,
+            [other.as_str()]
+        );
+        assert!(members(&store, store.route_key("GuildsId"))
+            .await
+            .is_empty());
+        assert!(members(&store, store.path_key("/guilds/1/roles"))
+            .await
+            .is_empty());
+        assert!(members(&store, store.children_key("/guilds/1"))
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at TEST_REDIS_URL"]
+    async fn remove_related_removes_children_and_routes() {
+        let store = store().await;
+        write(&store, "GuildsIdRoles", "/guilds/1/roles").await;
+        write(&store, "GuildsIdChannels", "/guilds/1/channels").await;
+        write(&store, "GuildsIdRoles", "/guilds/2/roles").await;
+        let other = write(&store, "ChannelsId", "/channels/4").await;
+
+        let routes = ["GuildsIdRoles".to_owned()];
+        assert_eq!(store.remove_related("/guilds/1", &routes).await, 3);
+
+        assert_eq!(indexed(&store).await, [other.as_str()]);
+        assert!(members(&store, store.children_key("/guilds"))
+            .await
+            .is_empty());
+        assert!(members(&store, store.route_key("GuildsIdRoles"))
+            .await
+            .is_empty());
+        assert_eq!(
+            members(&store, store.route_key("ChannelsId")).await,
+            [other.as_str()]
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at TEST_REDIS_URL"]
+    async fn remove_keeps_sets_consistent() {
+        let store = store().await;
+        let kept = write(&store, "GuildsIdRoles", "/guilds/1/roles").await;
+        let removed = write(&store, "GuildsIdRoles", "/guilds/2/roles").await;
+
+        let filter = removed.clone();
+        assert_eq!(store.remove(Box::new(move |key, _| key != filter)).await, 1);
+
+        assert!(store.read(&removed).await.is_none());
+        assert_eq!(indexed(&store).await, [kept.as_str()]);
+        assert_eq!(
+            members(&store, store.route_key("GuildsIdRoles")).await,
+            [kept.as_str()]
+        );
+        assert!(members(&store, store.path_key("/guilds/2/roles"))
+            .await
+            .is_empty());
+        assert!(members(&store, store.children_key("/guilds/2"))
+            .await
+            .is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+use ahash::AHashMap;
+use http::{response::Parts, HeaderMap, StatusCode, Version};
+use hyper::{body::Bytes, Body, Response};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch::{self, Receiver, Sender};
+
+/// A buffered response that can be handed to every coalesced request.
+pub struct SharedResponse {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl SharedResponse {
+    pub fn response(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.version_mut() = self.version;
+        *response.headers_mut() = self.headers.clone();
+
+        response
+    }
+}
+
+type Flights = Mutex<AHashMap<String, Receiver<Option<Arc<SharedResponse>>>>>;
+
+/// Identical GET requests that are currently being sent to Discord, so that
+/// requests arriving in the meantime can wait for the same response instead of
+/// sending their own.
+pub struct InFlight {
+    flights: Arc<Flights>,
+}
+
+pub enum Flight {
+    /// No identical request is in flight, this one has to be sent.
+    Leader(Leader),
+    /// An identical request is in flight, its response can be awaited.
+    Follower(Receiver<Option<Arc<SharedResponse>>>),
+}
+
+/// Handle of the request that is actually sent. Waiting requests are released
+/// when it is completed or dropped.
+pub struct Leader {
+    flights: Arc<Flights>,
+    key: String,
+    sender: Sender<Option<Arc<SharedResponse>>>,
+}
+
+impl Leader {
+    /// Whether any request is waiting for the response. Requests that join
+    /// after the leader was dropped without completing send their own.
+    pub fn has_followers(&self) -> bool {
+        // The map of flights holds a receiver too
+        self.sender.receiver_count() > 1
+    }
+
+    /// Hands the response to every waiting request.
+    pub fn complete(self, parts: &Parts, body: &Bytes) {
+        let response = SharedResponse {
+            status: parts.status,
+            version: parts.version,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+        };
+
+        self.sender.send_replace(Some(Arc::new(response)));
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.flights
+            .lock()
+            .expect("in flight requests got poisoned")
+            .remove(&self.key);
+    }
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self {
+            flights: Arc::default(),
+        }
+    }
+
+    pub fn join(&self, key: String) -> Flight {
+        let mut flights = self
+            .flights
+            .lock()
+            .expect("in flight requests got poisoned");
+
+        if let Some(receiver) = flights.get(&key) {
+            return Flight::Follower(receiver.clone());
+        }
+
+        let (sender, receiver) = watch::channel(None);
+        flights.insert(key.clone(), receiver);
+
+        Flight::Leader(Leader {
+            flights: self.flights.clone(),
+            key,
+            sender,
+        })
+    }
+}
+
+/// Waits for the response of the request in flight, or returns `None` if it
+/// failed without one.
+pub async fn wait(
+    mut receiver: Receiver<Option<Arc<SharedResponse>>>,
+) -> Option<Arc<SharedResponse>> {
+    loop {
+        if let Some(response) = receiver.borrow_and_update().clone() {
+            return Some(response);
+        }
+
+        if receiver.changed().await.is_err() {
+            return receiver.borrow().clone();
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use http::{
+    header::{
+        CACHE_CONTROL, CONTENT_LENGTH, DATE, ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        LAST_MODIFIED, TRANSFER_ENCODING,
+    },
+    HeaderMap, HeaderValue, StatusCode,
+};
+use hyper::{Body, Response};
+use std::time::SystemTime;
+
+/// Validators a client sent with a conditional GET request.
+pub struct Conditions {
+    if_none_match: Option<HeaderValue>,
+    if_modified_since: Option<HeaderValue>,
+}
+
+impl Conditions {
+    pub fn new(headers: &HeaderMap) -> Self {
+        Self {
+            if_none_match: headers.get(IF_NONE_MATCH).cloned(),
+            if_modified_since: headers.get(IF_MODIFIED_SINCE).cloned(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.if_none_match.is_none() && self.if_modified_since.is_none()
+    }
+
+    /// Whether the client's copy of a response with these headers is still
+    /// current. `If-Modified-Since` is only used without `If-None-Match`.
+    pub fn matches(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = &self.if_none_match {
+            let etag = match headers.get(ETAG).and_then(|etag| etag.to_str().ok()) {
+                Some(etag) => etag,
+                None => return false,
+            };
+
+            let tags = match if_none_match.to_str() {
+                Ok(tags) => tags,
+                Err(_) => return false,
+            };
+
+            return tags
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || weak_eq(tag, etag));
+        }
+
+        match (&self.if_modified_since, headers.get(LAST_MODIFIED)) {
+            (Some(since), Some(modified)) => match (parse_date(since), parse_date(modified)) {
+                (Some(since), Some(modified)) => modified <= since,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Compares entity tags, ignoring whether they are weak.
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+fn parse_date(value: &HeaderValue) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.to_str().ok()?).ok()
+}
+
+/// Whether the headers contain validators that Discord can revalidate the
+/// response with.
+pub fn has_validators(headers: &HeaderMap) -> bool {
+    headers.contains_key(ETAG) || headers.contains_key(LAST_MODIFIED)
+}
+
+/// Adds conditional headers revalidating a cached response to a request.
+pub fn add_validators(request: &mut HeaderMap, cached: &HeaderMap) {
+    if let Some(etag) = cached.get(ETAG) {
+        request.insert(IF_NONE_MATCH, etag.clone());
+    }
+
+    if let Some(last_modified) = cached.get(LAST_MODIFIED) {
+        request.insert(IF_MODIFIED_SINCE, last_modified.clone());
+    }
+}
+
+/// Updates the headers of a cached response with those of a `304 Not
+/// Modified` response to its revalidation.
+pub fn merge_headers(cached: &mut HeaderMap, not_modified: &HeaderMap) {
+    for name in not_modified.keys() {
+        if name == CONTENT_LENGTH || name == TRANSFER_ENCODING {
+            continue;
+        }
+
+        cached.remove(name);
+        for value in not_modified.get_all(name) {
+            cached.append(name.clone(), value.clone());
+        }
+    }
+}
+
+/// Returns a `304 Not Modified` response for a cached response with these
+/// headers.
+pub fn not_modified(headers: &HeaderMap) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NOT_MODIFIED;
+
+    for name in [CACHE_CONTROL, DATE, ETAG, EXPIRES, LAST_MODIFIED] {
+        if let Some(value) = headers.get(&name) {
+            response.headers_mut().insert(name, value.clone());
+        }
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Conditions;
+    use http::{
+        header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+        HeaderMap, HeaderName,
+    };
+
+    fn headers(pairs: &[(HeaderName, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), value.parse().unwrap()))
+            .collect()
+    }
+
+    fn matches(
+        request: &[(HeaderName, &'static str)],
+        response: &[(HeaderName, &'static str)],
+    ) -> bool {
+        Conditions::new(&headers(request)).matches(&headers(response))
+    }
+
+    #[test]
+    fn strong_and_weak_tags_match() {
+        assert!(matches(&[(IF_NONE_MATCH, "\"v1\"")], &[(ETAG, "\"v1\"")]));
+        assert!(matches(&[(IF_NONE_MATCH, "W/\"v1\"")], &[(ETAG, "\"v1\"")]));
+        assert!(matches(&[(IF_NONE_MATCH, "\"v1\"")], &[(ETAG, "W/\"v1\"")]));
+        assert!(!matches(&[(IF_NONE_MATCH, "\"v1\"")], &[(ETAG, "\"v2\"")]));
+    }
+
+    #[test]
+    fn wildcard_matches_any_tag() {
+        assert!(matches(&[(IF_NONE_MATCH, "*")], &[(ETAG, "\"v1\"")]));
+        assert!(!matches(&[(IF_NONE_MATCH, "*")], &[]));
+    }
+
+    #[test]
+    fn any_tag_of_list_matches() {
+        let request = [(IF_NONE_MATCH, "\"v1\", W/\"v2\",\"v3\"")];
+
+        assert!(matches(&request, &[(ETAG, "\"v2\"")]));
+        assert!(matches(&request, &[(ETAG, "\"v3\"")]));
+        assert!(!matches(&request, &[(ETAG, "\"v4\"")]));
+    }
+
+    #[test]
+    fn modified_since_compares_dates() {
+        let request = [(IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT")];
+
+        assert!(matches(
+            &request,
+            &[(LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT")]
+        ));
+        assert!(matches(
+            &request,
+            &[(LAST_MODIFIED, "Tue, 20 Oct 2015 07:28:00 GMT")]
+        ));
+        assert!(!matches(
+            &request,
+            &[(LAST_MODIFIED, "Thu, 22 Oct 2015 07:28:00 GMT")]
+        ));
+        assert!(!matches(&request, &[]));
+    }
+
+    #[test]
+    fn modified_since_is_ignored_with_none_match() {
+        let request = [
+            (IF_NONE_MATCH, "\"v1\""),
+            (IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT"),
+        ];
+        let unmodified = (LAST_MODIFIED, "Tue, 20 Oct 2015 07:28:00 GMT");
+
+        assert!(!matches(&request, &[(ETAG, "\"v2\""), unmodified.clone()]));
+        assert!(matches(&request, &[(ETAG, "\"v1\""), unmodified]));
+    }
+}
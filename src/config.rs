@@ -0,0 +1,1387 @@
+use crate::upstream::Upstream;
+use lazy_static::lazy_static;
+use redis::{
+    aio::{ConnectionManager, ConnectionManagerConfig},
+    Client, RedisResult,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env,
+    error::Error,
+    fmt::{Debug, Display, Formatter, Result as FmtResult},
+    fs, io,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+
+lazy_static! {
+    static ref CURRENT: RwLock<Arc<Config>> = RwLock::new(Arc::new(Config::default()));
+}
+
+/// Returns the configuration the proxy is currently running with.
+pub fn current() -> Arc<Config> {
+    CURRENT.read().expect("config got poisoned").clone()
+}
+
+pub fn set(config: Config) {
+    *CURRENT.write().expect("config got poisoned") = Arc::new(config);
+}
+
+/// Loads the configuration again and makes it the current one.
+///
+/// Settings that are only read on startup keep their current values.
+pub fn reload() -> Result<Arc<Config>, ConfigError> {
+    let mut config = Config::load()?;
+    config.keep_startup_settings(&current());
+    set(config);
+
+    Ok(current())
+}
+
+/// A string that is never printed, so that configuration dumps don't leak
+/// tokens.
+#[derive(Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        if self.0.is_empty() {
+            f.write_str("\"\"")
+        } else {
+            f.write_str("\"<redacted>\"")
+        }
+    }
+}
+
+impl FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_owned()))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Refuse to start if any value is invalid instead of falling back to
+    /// its default.
+    pub strict: bool,
+    pub discord_token: Secret,
+    pub host: IpAddr,
+    pub port: u16,
+    pub upstream_url: Upstream,
+    pub disable_http2: bool,
+    pub log_filter: String,
+    /// Let identical GET requests that arrive while one is in flight wait
+    /// for its response instead of sending their own.
+    pub coalesce_requests: bool,
+    pub cache: CacheConfig,
+    pub clients: ClientsConfig,
+    pub retries: RetriesConfig,
+    pub queues: QueuesConfig,
+    pub invalid_requests: InvalidRequestsConfig,
+    pub quarantine: QuarantineConfig,
+    pub read_only: ReadOnlyConfig,
+    pub auth: AuthConfig,
+    pub vault: VaultConfig,
+    pub policy: PolicyConfig,
+    pub redis: RedisConfig,
+    pub metrics: MetricsConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            strict: false,
+            discord_token: Secret::default(),
+            host: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 80,
+            upstream_url: Upstream::default(),
+            disable_http2: false,
+            log_filter: "info".into(),
+            coalesce_requests: true,
+            cache: CacheConfig::default(),
+            clients: ClientsConfig::default(),
+            retries: RetriesConfig::default(),
+            queues: QueuesConfig::default(),
+            invalid_requests: InvalidRequestsConfig::default(),
+            quarantine: QuarantineConfig::default(),
+            read_only: ReadOnlyConfig::default(),
+            auth: AuthConfig::default(),
+            vault: VaultConfig::default(),
+            policy: PolicyConfig::default(),
+            redis: RedisConfig::default(),
+            metrics: MetricsConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// How long responses are cached for routes without their own TTL, in
+    /// seconds.
+    pub duration: u64,
+    /// Size of all cached responses at which the least recently used ones are
+    /// evicted, in bytes.
+    pub max_bytes: u64,
+    /// How long after expiring a response is still served while it is
+    /// refreshed in the background, in seconds.
+    pub stale_while_revalidate: u64,
+    /// How long after expiring a response is still served if Discord can't
+    /// be reached or responds with a server error, in seconds.
+    pub stale_if_error: u64,
+    /// How long after expiring a response with an `ETag` or `Last-Modified`
+    /// header is kept to be revalidated with a conditional request, in
+    /// seconds.
+    pub revalidation_window: u64,
+    /// Caching settings per route, keyed by the name of the
+    /// `twilight_http_ratelimiting::Path` variant.
+    pub routes: BTreeMap<String, CacheRouteConfig>,
+    /// Cached routes whose entries are all evicted after a successful
+    /// mutation of a route, keyed by the mutated route. This is in addition
+    /// to entries for the mutated path itself, its parents and children.
+    pub invalidate: BTreeMap<String, Vec<String>>,
+    /// Where cached responses are kept.
+    pub store: CacheStoreKind,
+    /// Directory the `disk` store keeps cached responses in.
+    pub directory: Option<PathBuf>,
+    pub negative: NegativeCacheConfig,
+}
+
+impl CacheConfig {
+    /// Routes that are cached unless configured otherwise. Invites may
+    /// contain data that depends on who requested them.
+    const DEFAULT_ROUTES: &'static [(&'static str, CachePartition)] = &[
+        ("InvitesCode", CachePartition::Token),
+        ("UsersId", CachePartition::Shared),
+    ];
+
+    /// Routes whose responses are the same no matter the token, which are
+    /// shared between all tokens unless configured otherwise.
+    const SHARED_ROUTES: &'static [&'static str] = &[
+        "Gateway",
+        "StickerPacks",
+        "Stickers",
+        "UsersId",
+        "VoiceRegions",
+    ];
+
+    /// Mutations affecting routes outside of the mutated path, unless
+    /// configured otherwise.
+    const DEFAULT_INVALIDATIONS: &'static [(&'static str, &'static [&'static str])] = &[
+        ("ChannelsId", &["GuildsIdChannels"]),
+        ("ChannelsIdInvites", &["GuildsIdInvites"]),
+        ("InvitesCode", &["ChannelsIdInvites", "GuildsIdInvites"]),
+    ];
+
+    /// How GET responses for the route are cached, or `None` if they aren't.
+    pub fn route(&self, route: &str) -> Option<CacheRoute> {
+        let default = Self::DEFAULT_ROUTES
+            .iter()
+            .find(|(name, _)| *name == route)
+            .map(|(_, partition)| *partition);
+
+        let config = match (self.routes.get(route), default) {
+            (Some(config), _) => config.clone(),
+            (None, Some(_)) => CacheRouteConfig::default(),
+            (None, None) => CacheRouteConfig {
+                enabled: false,
+                ..CacheRouteConfig::default()
+            },
+        };
+
+        let ttl = config
+            .enabled
+            .then(|| Duration::from_secs(config.ttl.unwrap_or(self.duration)));
+        let negative_ttl = self.negative.route_ttl(route);
+
+        if ttl.is_none() && negative_ttl.is_none() {
+            return None;
+        }
+
+        // Whether something doesn't exist may depend on the token, e.g. for
+        // messages in channels it can't see
+        let fallback = if ttl.is_some() && Self::SHARED_ROUTES.contains(&route) {
+            CachePartition::Shared
+        } else {
+            CachePartition::Token
+        };
+
+        Some(CacheRoute {
+            ttl,
+            negative_ttl,
+            partition: config.partition.or(default).unwrap_or(fallback),
+        })
+    }
+
+    /// Names of all routes that are cached.
+    pub fn cached_routes(&self) -> Vec<&str> {
+        let mut routes: Vec<&str> = self
+            .routes
+            .iter()
+            .filter(|(_, route)| route.enabled)
+            .map(|(name, _)| name.as_str())
+            .collect();
+
+        for (route, _) in Self::DEFAULT_ROUTES {
+            if !self.routes.contains_key(*route) {
+                routes.push(route);
+            }
+        }
+
+        for route in self.negative.routes() {
+            if !routes.contains(&route) {
+                routes.push(route);
+            }
+        }
+
+        routes
+    }
+
+    /// Cached routes whose entries are all evicted after a successful
+    /// mutation of the route.
+    pub fn invalidated_routes(&self, route: &str) -> Vec<String> {
+        if let Some(routes) = self.invalidate.get(route) {
+            return routes.clone();
+        }
+
+        Self::DEFAULT_INVALIDATIONS
+            .iter()
+            .find(|(mutated, _)| *mutated == route)
+            .map(|(_, routes)| routes.iter().map(|route| (*route).to_owned()).collect())
+            .unwrap_or_default()
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            duration: 60 * 10,
+            max_bytes: 64 * 1024 * 1024,
+            stale_while_revalidate: 0,
+            stale_if_error: 0,
+            revalidation_window: 60 * 10,
+            routes: BTreeMap::new(),
+            invalidate: BTreeMap::new(),
+            store: CacheStoreKind::Memory,
+            directory: None,
+            negative: NegativeCacheConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheRouteConfig {
+    pub enabled: bool,
+    /// How long responses are cached, in seconds. Defaults to the cache's
+    /// `duration`.
+    pub ttl: Option<u64>,
+    /// Who cached responses are shared between.
+    pub partition: Option<CachePartition>,
+}
+
+impl Default for CacheRouteConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl: None,
+            partition: None,
+        }
+    }
+}
+
+/// Who cached responses for a route are shared between.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CachePartition {
+    /// All requests, no matter the token.
+    Shared,
+    /// Requests using the same token.
+    Token,
+    /// Requests using a token of the same application, such as after a token
+    /// was reset.
+    Application,
+}
+
+/// Where cached responses are kept.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheStoreKind {
+    /// In memory, losing them on restart.
+    Memory,
+    /// In files of `cache.directory`, keeping them across restarts.
+    Disk,
+    /// On the Redis server in `redis.url`, shared between replicas.
+    Redis,
+}
+
+impl FromStr for CacheStoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(Self::Memory),
+            "disk" => Ok(Self::Disk),
+            "redis" => Ok(Self::Redis),
+            _ => Err(format!("unknown cache store {:?}", s)),
+        }
+    }
+}
+
+/// Caching of `404 Not Found` responses, so that repeated lookups of things
+/// that don't exist don't cost a ratelimit ticket each.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NegativeCacheConfig {
+    /// How long 404 responses are cached, in seconds. 0 disables negative
+    /// caching.
+    pub ttl: u64,
+    /// Routes whose 404 responses are cached, named after the
+    /// `twilight_http_ratelimiting::Path` variant.
+    pub routes: Vec<String>,
+}
+
+impl NegativeCacheConfig {
+    /// How long 404 responses for the route are cached, or `None` if they
+    /// aren't.
+    pub fn route_ttl(&self, route: &str) -> Option<Duration> {
+        (self.ttl > 0 && self.routes.iter().any(|name| name == route))
+            .then(|| Duration::from_secs(self.ttl))
+    }
+
+    fn routes(&self) -> Vec<&str> {
+        if self.ttl == 0 {
+            return Vec::new();
+        }
+
+        self.routes.iter().map(String::as_str).collect()
+    }
+}
+
+impl Default for NegativeCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl: 30,
+            routes: vec![
+                "ChannelsIdMessagesId".into(),
+                "InvitesCode".into(),
+                "UsersId".into(),
+            ],
+        }
+    }
+}
+
+/// Effective caching settings of a route.
+#[derive(Clone, Copy, Debug)]
+pub struct CacheRoute {
+    /// How long successful responses are cached, if they are.
+    pub ttl: Option<Duration>,
+    /// How long 404 responses are cached, if they are.
+    pub negative_ttl: Option<Duration>,
+    pub partition: CachePartition,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientsConfig {
+    /// Interval at which unused ratelimiters are checked for decay, in
+    /// seconds.
+    pub reap_interval: u64,
+    /// Time after which an unused ratelimiter is dropped, in seconds.
+    pub decay_timeout: u64,
+    /// Maximum amount of ratelimiters kept for tokens other than the default
+    /// one.
+    pub cache_max_size: Option<usize>,
+    /// Where ratelimit buckets are tracked.
+    pub ratelimiter: RatelimiterKind,
+    /// File that in-memory ratelimit buckets are saved to and restored from
+    /// on startup.
+    pub snapshot_file: Option<PathBuf>,
+    /// Interval at which ratelimit buckets are saved, in seconds. They are
+    /// also saved when shutting down.
+    pub snapshot_interval: u64,
+}
+
+/// Where ratelimit buckets are tracked.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RatelimiterKind {
+    /// In memory, separately for every replica.
+    Memory,
+    /// On the Redis server in `redis.url`, shared between replicas.
+    Redis,
+}
+
+impl FromStr for RatelimiterKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(Self::Memory),
+            "redis" => Ok(Self::Redis),
+            _ => Err(format!("unknown ratelimiter {:?}", s)),
+        }
+    }
+}
+
+impl Default for ClientsConfig {
+    fn default() -> Self {
+        Self {
+            reap_interval: 600,
+            decay_timeout: 3600,
+            cache_max_size: None,
+            ratelimiter: RatelimiterKind::Memory,
+            snapshot_file: None,
+            snapshot_interval: 60,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetriesConfig {
+    /// Retry requests that were ratelimited by Discord inside the proxy
+    /// instead of passing the 429 on to the client.
+    pub enabled: bool,
+    pub max_retries: u32,
+    /// Longest `retry_after` that is waited out, in seconds. Responses asking
+    /// for longer waits are passed on to the client.
+    pub max_wait: u64,
+}
+
+impl Default for RetriesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_retries: 3,
+            max_wait: 60,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueuesConfig {
+    /// Maximum amount of requests waiting for a ticket of the same bucket
+    /// and token.
+    pub max_per_bucket: Option<usize>,
+    /// Maximum amount of requests waiting for a ticket of the same token.
+    pub max_per_token: Option<usize>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InvalidRequestsConfig {
+    /// Length of the sliding window invalid requests are counted in, in
+    /// seconds.
+    pub window: u64,
+    /// Counts of invalid requests, globally or for a single token, at which a
+    /// warning is logged.
+    pub warn_at: Vec<u32>,
+    /// Count of invalid requests at which all requests are refused until the
+    /// window cools down.
+    pub limit: u32,
+    /// Count of invalid requests at which requests using the same token are
+    /// refused until the window cools down.
+    pub token_limit: Option<u32>,
+}
+
+impl Default for InvalidRequestsConfig {
+    fn default() -> Self {
+        Self {
+            window: 60 * 10,
+            warn_at: vec![5000, 7500],
+            limit: 9000,
+            token_limit: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuarantineConfig {
+    /// How long requests using a token that Discord rejected with a 401 are
+    /// answered by the proxy itself, in seconds. 0 disables the quarantine.
+    pub cooldown: u64,
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> Self {
+        Self { cooldown: 60 * 10 }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadOnlyConfig {
+    /// Whether the proxy starts in read-only mode, refusing everything but
+    /// GET requests. Can be toggled at runtime.
+    pub enabled: bool,
+    /// Value of `Retry-After` on refused requests, in seconds.
+    pub retry_after: u64,
+}
+
+impl Default for ReadOnlyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            retry_after: 60,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Require clients to authenticate with one of the keys in `clients`.
+    pub required: bool,
+    pub public_health: bool,
+    pub public_metrics: bool,
+    pub clients: Vec<ClientKeyConfig>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            required: false,
+            public_health: true,
+            public_metrics: true,
+            clients: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientKeyConfig {
+    pub name: String,
+    pub key: Secret,
+    /// Discord tokens this client may use, `"default"` referring to the
+    /// default token. Any token may be used if this is not set.
+    pub tokens: Option<Vec<Secret>>,
+    /// Whether this client may use the admin endpoints.
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// Aliases for tokens, so that clients can send `Authorization: Alias <name>`
+/// instead of holding real tokens.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VaultConfig {
+    /// Directory containing one file per alias, named after the alias and
+    /// containing its token. Entries take precedence over `aliases`.
+    pub directory: Option<PathBuf>,
+    pub aliases: BTreeMap<String, Secret>,
+}
+
+impl VaultConfig {
+    fn load_directory(&mut self, directory: &Path, problems: &mut Vec<String>) {
+        let entries = match fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                problems.push(format!(
+                    "Unable to read vault directory {}: {}",
+                    directory.display(),
+                    e
+                ));
+                return;
+            }
+        };
+
+        for entry in entries.flatten() {
+            let alias = match entry.file_name().into_string() {
+                Ok(alias) => alias,
+                Err(alias) => {
+                    problems.push(format!("Vault alias {:?} is not UTF-8", alias));
+                    continue;
+                }
+            };
+
+            // Skip things like the `..data` links in Kubernetes secret mounts
+            if alias.starts_with('.') || entry.path().is_dir() {
+                continue;
+            }
+
+            match fs::read_to_string(entry.path()) {
+                Ok(token) => {
+                    self.aliases.insert(alias, Secret(token.trim().to_owned()));
+                }
+                Err(e) => problems.push(format!("Unable to read vault alias {}: {}", alias, e)),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    Allow,
+    Deny,
+}
+
+/// Rules deciding which requests may be sent to Discord at all.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Action taken if no rule matches.
+    pub default_action: PolicyAction,
+    pub rules: Vec<PolicyRule>,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            default_action: PolicyAction::Allow,
+            rules: Vec::new(),
+        }
+    }
+}
+
+/// A rule matching requests by all of the given criteria. Criteria that are
+/// not set match every request.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRule {
+    pub action: PolicyAction,
+    /// HTTP methods, such as `DELETE`.
+    pub methods: Option<Vec<String>>,
+    /// Names of `twilight_http_ratelimiting::Path` variants, such as
+    /// `GuildsIdBans`.
+    pub routes: Option<Vec<String>>,
+    /// Tokens, token aliases or `"default"`.
+    pub tokens: Option<Vec<Secret>>,
+    /// Names of authenticated clients.
+    pub clients: Option<Vec<String>>,
+}
+
+/// Connection to a Redis-compatible server shared between proxy replicas.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    /// URL of the server, such as `redis://127.0.0.1:6379/0`. It may contain a
+    /// password.
+    pub url: Option<Secret>,
+    /// Prepended to all keys, so that several deployments can share a server.
+    pub key_prefix: String,
+    /// Time after which connecting or a command fails, in milliseconds.
+    pub timeout: u64,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            key_prefix: "http-proxy:".into(),
+            timeout: 1000,
+        }
+    }
+}
+
+impl RedisConfig {
+    /// Connects to the server, reconnecting automatically whenever the
+    /// connection is lost.
+    pub async fn connect(&self) -> RedisResult<ConnectionManager> {
+        let url = self.url.as_ref().map(Secret::expose).unwrap_or_default();
+        let timeout = Duration::from_millis(self.timeout);
+
+        let manager_config = ConnectionManagerConfig::new()
+            .set_number_of_retries(1)
+            .set_connection_timeout(timeout)
+            .set_response_timeout(timeout);
+
+        ConnectionManager::new_with_config(Client::open(url)?, manager_config).await
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub key: String,
+    /// Time after which idle metrics are no longer reported, in seconds.
+    pub timeout: u64,
+    pub track_in_progress: bool,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            key: "twilight_http_proxy".into(),
+            timeout: 300,
+            track_in_progress: false,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from the file in `CONFIG_FILE`, if any, and
+    /// applies overrides from the environment on top of it.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match env::var_os("CONFIG_FILE") {
+            Some(path) => Self::from_file(PathBuf::from(path))?,
+            None => Self::default(),
+        };
+
+        let mut problems = Vec::new();
+        // Problems that refuse loading even if the configuration isn't strict
+        let mut fatal = Vec::new();
+
+        override_from_env(&mut config.strict, "STRICT_CONFIG", &mut problems);
+        override_from_env(&mut config.discord_token, "DISCORD_TOKEN", &mut problems);
+        // Listening somewhere else than intended is no sensible default
+        override_from_env(&mut config.host, "HOST", &mut fatal);
+        override_from_env(&mut config.port, "PORT", &mut fatal);
+        override_from_env(&mut config.upstream_url, "UPSTREAM_URL", &mut problems);
+        override_from_env(&mut config.log_filter, "RUST_LOG", &mut problems);
+        override_from_env(
+            &mut config.coalesce_requests,
+            "COALESCE_REQUESTS",
+            &mut problems,
+        );
+        override_from_env(&mut config.cache.duration, "CACHE_DURATION", &mut problems);
+        override_from_env(
+            &mut config.cache.max_bytes,
+            "CACHE_MAX_BYTES",
+            &mut problems,
+        );
+        override_from_env(
+            &mut config.cache.stale_while_revalidate,
+            "CACHE_STALE_WHILE_REVALIDATE",
+            &mut problems,
+        );
+        override_from_env(
+            &mut config.cache.stale_if_error,
+            "CACHE_STALE_IF_ERROR",
+            &mut problems,
+        );
+        override_from_env(
+            &mut config.cache.revalidation_window,
+            "CACHE_REVALIDATION_WINDOW",
+            &mut problems,
+        );
+        override_from_env(
+            &mut config.cache.negative.ttl,
+            "CACHE_NEGATIVE_TTL",
+            &mut problems,
+        );
+        override_from_env(&mut config.cache.store, "CACHE_STORE", &mut problems);
+        if let Some(directory) = from_env("CACHE_DIRECTORY", &mut problems) {
+            config.cache.directory = Some(directory);
+        }
+        override_from_env(
+            &mut config.clients.reap_interval,
+            "CLIENT_REAP_INTERVAL",
+            &mut problems,
+        );
+        override_from_env(
+            &mut config.clients.decay_timeout,
+            "CLIENT_DECAY_TIMEOUT",
+            &mut problems,
+        );
+        if let Some(max_size) = from_env("CLIENT_CACHE_MAX_SIZE", &mut problems) {
+            config.clients.cache_max_size = Some(max_size);
+        }
+        override_from_env(
+            &mut config.clients.ratelimiter,
+            "CLIENT_RATELIMITER",
+            &mut problems,
+        );
+        if let Some(file) = from_env("CLIENT_SNAPSHOT_FILE", &mut problems) {
+            config.clients.snapshot_file = Some(file);
+        }
+        override_from_env(
+            &mut config.clients.snapshot_interval,
+            "CLIENT_SNAPSHOT_INTERVAL",
+            &mut problems,
+        );
+        override_from_env(
+            &mut config.retries.enabled,
+            "RETRIES_ENABLED",
+            &mut problems,
+        );
+        override_from_env(
+            &mut config.retries.max_retries,
+            "RETRIES_MAX",
+            &mut problems,
+        );
+        override_from_env(
+            &mut config.retries.max_wait,
+            "RETRIES_MAX_WAIT",
+            &mut problems,
+        );
+        if let Some(max) = from_env("QUEUE_MAX_PER_BUCKET", &mut problems) {
+            config.queues.max_per_bucket = Some(max);
+        }
+        if let Some(max) = from_env("QUEUE_MAX_PER_TOKEN", &mut problems) {
+            config.queues.max_per_token = Some(max);
+        }
+        override_from_env(
+            &mut config.invalid_requests.window,
+            "INVALID_REQUESTS_WINDOW",
+            &mut problems,
+        );
+        override_from_env(
+            &mut config.invalid_requests.limit,
+            "INVALID_REQUESTS_LIMIT",
+            &mut problems,
+        );
+        if let Some(limit) = from_env("INVALID_REQUESTS_TOKEN_LIMIT", &mut problems) {
+            config.invalid_requests.token_limit = Some(limit);
+        }
+        override_from_env(
+            &mut config.quarantine.cooldown,
+            "QUARANTINE_COOLDOWN",
+            &mut problems,
+        );
+        override_from_env(&mut config.read_only.enabled, "READ_ONLY", &mut problems);
+        override_from_env(
+            &mut config.read_only.retry_after,
+            "READ_ONLY_RETRY_AFTER",
+            &mut problems,
+        );
+        override_from_env(&mut config.auth.required, "AUTH_REQUIRED", &mut problems);
+        override_from_env(
+            &mut config.auth.public_health,
+            "AUTH_PUBLIC_HEALTH",
+            &mut problems,
+        );
+        override_from_env(
+            &mut config.auth.public_metrics,
+            "AUTH_PUBLIC_METRICS",
+            &mut problems,
+        );
+        if let Some(directory) = from_env("VAULT_DIRECTORY", &mut problems) {
+            config.vault.directory = Some(directory);
+        }
+        override_from_env(&mut config.metrics.key, "METRIC_KEY", &mut problems);
+        if let Some(url) = from_env("REDIS_URL", &mut problems) {
+            config.redis.url = Some(url);
+        }
+        override_from_env(
+            &mut config.redis.key_prefix,
+            "REDIS_KEY_PREFIX",
+            &mut problems,
+        );
+        override_from_env(&mut config.redis.timeout, "REDIS_TIMEOUT", &mut problems);
+        override_from_env(&mut config.metrics.timeout, "METRIC_TIMEOUT", &mut problems);
+        override_from_env(
+            &mut config.metrics.track_in_progress,
+            "TRACK_IN_PROGRESS",
+            &mut problems,
+        );
+
+        // Historically any value enabled this
+        if env::var_os("DISABLE_HTTP2").is_some() {
+            config.disable_http2 = true;
+        }
+
+        if let Some(directory) = config.vault.directory.clone() {
+            config.vault.load_directory(&directory, &mut problems);
+        }
+
+        config.validate(&mut problems);
+        config.validate_policy(&mut fatal);
+
+        if config.discord_token.expose().is_empty() {
+            return Err(ConfigError::MissingToken);
+        }
+
+        if !fatal.is_empty() || (config.strict && !problems.is_empty()) {
+            fatal.extend(problems);
+            return Err(ConfigError::Invalid { problems: fatal });
+        }
+
+        if !problems.is_empty() {
+            for problem in problems {
+                warn!("{}, proceeding with defaults", problem);
+            }
+        }
+
+        Ok(config)
+    }
+
+    fn from_file(path: PathBuf) -> Result<Self, ConfigError> {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(source) => return Err(ConfigError::Read { path, source }),
+        };
+
+        toml::from_str(&contents).map_err(|source| ConfigError::Parse { path, source })
+    }
+
+    fn keep_startup_settings(&mut self, current: &Self) {
+        if self.host != current.host || self.port != current.port {
+            warn!("Changing the address to listen on requires a restart");
+            self.host = current.host;
+            self.port = current.port;
+        }
+
+        if self.upstream_url != current.upstream_url {
+            warn!("Changing upstream_url requires a restart");
+            self.upstream_url = current.upstream_url.clone();
+        }
+
+        if self.disable_http2 != current.disable_http2 {
+            warn!("Changing disable_http2 requires a restart");
+            self.disable_http2 = current.disable_http2;
+        }
+
+        if self.cache.store != current.cache.store
+            || self.cache.directory != current.cache.directory
+        {
+            warn!("Changing cache.store or cache.directory requires a restart");
+            self.cache.store = current.cache.store;
+            self.cache.directory = current.cache.directory.clone();
+        }
+
+        if self.clients.ratelimiter != current.clients.ratelimiter {
+            warn!("Changing clients.ratelimiter requires a restart");
+            self.clients.ratelimiter = current.clients.ratelimiter;
+        }
+
+        if self.clients.snapshot_file != current.clients.snapshot_file {
+            warn!("Changing clients.snapshot_file requires a restart");
+            self.clients.snapshot_file = current.clients.snapshot_file.clone();
+        }
+
+        if self.redis != current.redis {
+            warn!("Changing redis settings requires a restart");
+            self.redis = current.redis.clone();
+        }
+
+        if self.metrics != current.metrics {
+            warn!("Changing metrics settings requires a restart");
+            self.metrics = current.metrics.clone();
+        }
+    }
+
+    /// Resets invalid values to their defaults, recording a problem for each.
+    fn validate(&mut self, problems: &mut Vec<String>) {
+        let defaults = Self::default();
+
+        if EnvFilter::try_new(&self.log_filter).is_err() {
+            problems.push(format!("log_filter {:?} is invalid", self.log_filter));
+            self.log_filter = defaults.log_filter;
+        }
+
+        if self.cache.max_bytes == 0 {
+            problems.push("cache.max_bytes must be greater than 0".into());
+            self.cache.max_bytes = defaults.cache.max_bytes;
+        }
+
+        if self.cache.store == CacheStoreKind::Disk && self.cache.directory.is_none() {
+            problems.push("cache.directory must be set to use the disk cache store".into());
+            self.cache.store = defaults.cache.store;
+        }
+
+        if self.cache.store == CacheStoreKind::Redis && self.redis.url.is_none() {
+            problems.push("redis.url must be set to use the redis cache store".into());
+            self.cache.store = defaults.cache.store;
+        }
+
+        if self.clients.ratelimiter == RatelimiterKind::Redis && self.redis.url.is_none() {
+            problems.push("redis.url must be set to use the redis ratelimiter".into());
+            self.clients.ratelimiter = defaults.clients.ratelimiter;
+        }
+
+        if self.redis.timeout == 0 {
+            problems.push("redis.timeout must be greater than 0".into());
+            self.redis.timeout = defaults.redis.timeout;
+        }
+
+        if self.clients.reap_interval == 0 {
+            problems.push("clients.reap_interval must be greater than 0".into());
+            self.clients.reap_interval = defaults.clients.reap_interval;
+        }
+
+        if self.clients.snapshot_interval == 0 {
+            problems.push("clients.snapshot_interval must be greater than 0".into());
+            self.clients.snapshot_interval = defaults.clients.snapshot_interval;
+        }
+
+        if self.queues.max_per_bucket == Some(0) {
+            problems.push("queues.max_per_bucket must be greater than 0".into());
+            self.queues.max_per_bucket = defaults.queues.max_per_bucket;
+        }
+
+        if self.queues.max_per_token == Some(0) {
+            problems.push("queues.max_per_token must be greater than 0".into());
+            self.queues.max_per_token = defaults.queues.max_per_token;
+        }
+
+        if self.invalid_requests.window == 0 {
+            problems.push("invalid_requests.window must be greater than 0".into());
+            self.invalid_requests.window = defaults.invalid_requests.window;
+        }
+
+        if self.invalid_requests.limit == 0 {
+            problems.push("invalid_requests.limit must be greater than 0".into());
+            self.invalid_requests.limit = defaults.invalid_requests.limit;
+        }
+
+        if self.invalid_requests.token_limit == Some(0) {
+            problems.push("invalid_requests.token_limit must be greater than 0".into());
+            self.invalid_requests.token_limit = defaults.invalid_requests.token_limit;
+        }
+
+        // Durations this long are surely mistakes, and longer ones overflow
+        // when added to the current time
+        let durations = [
+            (
+                "cache.duration",
+                &mut self.cache.duration,
+                defaults.cache.duration,
+            ),
+            (
+                "cache.stale_while_revalidate",
+                &mut self.cache.stale_while_revalidate,
+                defaults.cache.stale_while_revalidate,
+            ),
+            (
+                "cache.stale_if_error",
+                &mut self.cache.stale_if_error,
+                defaults.cache.stale_if_error,
+            ),
+            (
+                "cache.revalidation_window",
+                &mut self.cache.revalidation_window,
+                defaults.cache.revalidation_window,
+            ),
+            (
+                "cache.negative.ttl",
+                &mut self.cache.negative.ttl,
+                defaults.cache.negative.ttl,
+            ),
+            (
+                "clients.reap_interval",
+                &mut self.clients.reap_interval,
+                defaults.clients.reap_interval,
+            ),
+            (
+                "clients.decay_timeout",
+                &mut self.clients.decay_timeout,
+                defaults.clients.decay_timeout,
+            ),
+            (
+                "clients.snapshot_interval",
+                &mut self.clients.snapshot_interval,
+                defaults.clients.snapshot_interval,
+            ),
+            (
+                "retries.max_wait",
+                &mut self.retries.max_wait,
+                defaults.retries.max_wait,
+            ),
+            (
+                "invalid_requests.window",
+                &mut self.invalid_requests.window,
+                defaults.invalid_requests.window,
+            ),
+            (
+                "quarantine.cooldown",
+                &mut self.quarantine.cooldown,
+                defaults.quarantine.cooldown,
+            ),
+            (
+                "read_only.retry_after",
+                &mut self.read_only.retry_after,
+                defaults.read_only.retry_after,
+            ),
+            (
+                "metrics.timeout",
+                &mut self.metrics.timeout,
+                defaults.metrics.timeout,
+            ),
+        ];
+
+        for (name, value, default) in durations {
+            if *value > MAX_DURATION {
+                problems.push(format!("{} must be at most {} seconds", name, MAX_DURATION));
+                *value = default;
+            }
+        }
+
+        for (route, config) in &mut self.cache.routes {
+            if config.ttl > Some(MAX_DURATION) {
+                problems.push(format!(
+                    "cache.routes.{}.ttl must be at most {} seconds",
+                    route, MAX_DURATION
+                ));
+                config.ttl = None;
+            }
+        }
+
+        if self
+            .auth
+            .clients
+            .iter()
+            .any(|client| client.key.expose().is_empty())
+        {
+            problems.push("auth.clients must not contain empty keys".into());
+            self.auth
+                .clients
+                .retain(|client| !client.key.expose().is_empty());
+        }
+
+        if self
+            .vault
+            .aliases
+            .values()
+            .any(|token| token.expose().is_empty())
+        {
+            problems.push("vault must not contain empty tokens".into());
+            self.vault
+                .aliases
+                .retain(|_, token| !token.expose().is_empty());
+        }
+
+        for route in self
+            .cache
+            .routes
+            .keys()
+            .filter(|route| !is_known_route(route))
+        {
+            problems.push(format!("cache.routes has unknown route {:?}", route));
+        }
+        self.cache.routes.retain(|route, _| is_known_route(route));
+
+        for (mutated, routes) in &self.cache.invalidate {
+            for route in std::iter::once(mutated).chain(routes) {
+                if !is_known_route(route) {
+                    problems.push(format!("cache.invalidate has unknown route {:?}", route));
+                }
+            }
+        }
+        self.cache
+            .invalidate
+            .retain(|route, _| is_known_route(route));
+        for routes in self.cache.invalidate.values_mut() {
+            routes.retain(|route| is_known_route(route));
+        }
+
+        for route in self
+            .cache
+            .negative
+            .routes
+            .iter()
+            .filter(|route| !is_known_route(route))
+        {
+            problems.push(format!(
+                "cache.negative.routes has unknown route {:?}",
+                route
+            ));
+        }
+        self.cache
+            .negative
+            .routes
+            .retain(|route| is_known_route(route));
+
+        if self.metrics.key.is_empty() {
+            problems.push("metrics.key must not be empty".into());
+            self.metrics.key = defaults.metrics.key;
+        }
+    }
+
+    /// Records policy rules that refer to unknown methods or routes. A rule
+    /// with a typo would never match and let through the requests it was
+    /// meant to deny, so these can't be ignored.
+    fn validate_policy(&self, problems: &mut Vec<String>) {
+        for rule in &self.policy.rules {
+            let unknown_methods = rule.methods.iter().flatten().filter(|method| {
+                !METHODS
+                    .iter()
+                    .any(|known| known.eq_ignore_ascii_case(method))
+            });
+            let unknown_routes = rule
+                .routes
+                .iter()
+                .flatten()
+                .filter(|route| !is_known_route(route));
+
+            let unknown: Vec<String> = unknown_methods
+                .map(|method| format!("method {:?}", method))
+                .chain(unknown_routes.map(|route| format!("route {:?}", route)))
+                .collect();
+
+            if !unknown.is_empty() {
+                problems.push(format!("policy rule has unknown {}", unknown.join(", ")));
+            }
+        }
+    }
+}
+
+/// Longest duration that can be configured, in seconds, which is ten years.
+const MAX_DURATION: u64 = 10 * 365 * 24 * 60 * 60;
+
+/// HTTP methods that rules can refer to.
+const METHODS: &[&str] = &["DELETE", "GET", "PATCH", "POST", "PUT"];
+
+/// Names of the `twilight_http_ratelimiting::Path` variants, which identify
+/// routes in the configuration.
+const ROUTES: &[&str] = &[
+    "ApplicationCommand",
+    "ApplicationCommandId",
+    "ApplicationGuildCommand",
+    "ApplicationGuildCommandId",
+    "ChannelsId",
+    "ChannelsIdFollowers",
+    "ChannelsIdInvites",
+    "ChannelsIdMessages",
+    "ChannelsIdMessagesBulkDelete",
+    "ChannelsIdMessagesId",
+    "ChannelsIdMessagesIdCrosspost",
+    "ChannelsIdMessagesIdReactions",
+    "ChannelsIdMessagesIdReactionsUserIdType",
+    "ChannelsIdMessagesIdThreads",
+    "ChannelsIdPermissionsOverwriteId",
+    "ChannelsIdPins",
+    "ChannelsIdPinsMessageId",
+    "ChannelsIdRecipients",
+    "ChannelsIdThreadMembers",
+    "ChannelsIdThreadMembersId",
+    "ChannelsIdThreads",
+    "ChannelsIdTyping",
+    "ChannelsIdWebhooks",
+    "Gateway",
+    "GatewayBot",
+    "Guilds",
+    "GuildsId",
+    "GuildsIdAuditLogs",
+    "GuildsIdAutoModerationRules",
+    "GuildsIdAutoModerationRulesId",
+    "GuildsIdBans",
+    "GuildsIdBansId",
+    "GuildsIdBansUserId",
+    "GuildsIdChannels",
+    "GuildsIdEmojis",
+    "GuildsIdEmojisId",
+    "GuildsIdIntegrations",
+    "GuildsIdIntegrationsId",
+    "GuildsIdIntegrationsIdSync",
+    "GuildsIdInvites",
+    "GuildsIdMembers",
+    "GuildsIdMembersId",
+    "GuildsIdMembersIdRolesId",
+    "GuildsIdMembersMeNick",
+    "GuildsIdMembersSearch",
+    "GuildsIdMfa",
+    "GuildsIdOnboarding",
+    "GuildsIdPreview",
+    "GuildsIdPrune",
+    "GuildsIdRegions",
+    "GuildsIdRoles",
+    "GuildsIdRolesId",
+    "GuildsIdScheduledEvents",
+    "GuildsIdScheduledEventsId",
+    "GuildsIdScheduledEventsIdUsers",
+    "GuildsIdStickers",
+    "GuildsIdTemplates",
+    "GuildsIdTemplatesCode",
+    "GuildsIdThreads",
+    "GuildsIdVanityUrl",
+    "GuildsIdVoiceStates",
+    "GuildsIdWebhooks",
+    "GuildsIdWelcomeScreen",
+    "GuildsIdWidget",
+    "GuildsIdWidgetJson",
+    "GuildsTemplatesCode",
+    "InteractionCallback",
+    "InvitesCode",
+    "OauthApplicationsMe",
+    "OauthMe",
+    "StageInstances",
+    "StickerPacks",
+    "Stickers",
+    "UsersId",
+    "UsersIdChannels",
+    "UsersIdConnections",
+    "UsersIdGuilds",
+    "UsersIdGuildsId",
+    "UsersIdGuildsIdMember",
+    "VoiceRegions",
+    "WebhooksId",
+    "WebhooksIdToken",
+    "WebhooksIdTokenMessagesId",
+];
+
+fn is_known_route(route: &str) -> bool {
+    ROUTES.contains(&route)
+}
+
+fn from_env<T: FromStr>(key: &str, problems: &mut Vec<String>) -> Option<T> {
+    match env::var_os(key)?.into_string() {
+        Ok(s) => {
+            let parsed = s.parse().ok();
+
+            if parsed.is_none() {
+                problems.push(format!("Unable to parse {}", key));
+            }
+
+            parsed
+        }
+        Err(s) => {
+            problems.push(format!("{} is not UTF-8: {:?}", key, s));
+            None
+        }
+    }
+}
+
+fn override_from_env<T: FromStr>(target: &mut T, key: &str, problems: &mut Vec<String>) {
+    if let Some(value) = from_env(key, problems) {
+        *target = value;
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Invalid {
+        problems: Vec<String>,
+    },
+    MissingToken,
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    Read {
+        path: PathBuf,
+        source: io::Error,
+    },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Invalid { problems } => {
+                f.write_str("invalid configuration: ")?;
+                f.write_str(&problems.join(", "))
+            }
+            Self::MissingToken => f.write_str("no discord token configured"),
+            Self::Parse { path, source } => {
+                write!(f, "failed to parse {}: ", path.display())?;
+                Display::fmt(source, f)
+            }
+            Self::Read { path, source } => {
+                write!(f, "failed to read {}: ", path.display())?;
+                Display::fmt(source, f)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
@@ -1,17 +1,39 @@
-use http::{uri::InvalidUri, Method, Response};
+use http::{
+    header::{PROXY_AUTHENTICATE, RETRY_AFTER},
+    uri::InvalidUri,
+    Method, Response,
+};
 use hyper::{Body, Error as HyperError};
 use std::{
     error::Error,
     fmt::{Display, Formatter, Result as FmtResult},
+    time::Duration,
 };
 use twilight_http_ratelimiting::request::PathParseError;
 
 static ACQUIRING_TICKET_FAILED_MSG: &str =
     "http-proxy: Acquiring ticket from the ratelimiter failed";
+static INVALID_REQUEST_LIMIT_MSG: &str =
+    "http-proxy: Too many invalid requests, refusing to contact the Discord API";
+static DEADLINE_EXCEEDED_MSG: &str =
+    "http-proxy: The request could not be sent before its deadline";
+static FORBIDDEN_MSG: &str = "http-proxy: The proxy does not allow this request";
+static NOT_FOUND_MSG: &str = "http-proxy: Unknown admin endpoint";
 static INVALID_URI_MSG: &str = "http-proxy: Failed to create URI for requesting Discord API";
 static INVALID_METHOD_MSG: &str = "http-proxy: Unsupported HTTP method in request";
 static INVALID_PATH_MSG: &str = "http-proxy: Failed to parse API path from client request";
+static MISSING_PARAMETER_MSG: &str = "http-proxy: Missing query parameter for admin endpoint";
+static PROXY_AUTH_REQUIRED_MSG: &str = "http-proxy: Missing or invalid credentials for the proxy";
+static QUEUE_FULL_MSG: &str =
+    "http-proxy: Too many requests are already waiting for this ratelimit";
+static QUARANTINED_TOKEN_MSG: &str =
+    "http-proxy: This token was recently rejected by the Discord API";
+static READ_ONLY_MSG: &str = "http-proxy: The proxy is read-only, only GET requests are allowed";
+static READING_BODY_MSG: &str = "http-proxy: Failed to read the request body";
 static REQUEST_ISSUE_MSG: &str = "http-proxy: Error requesting the Discord API";
+static TOKEN_NOT_ALLOWED_MSG: &str = "http-proxy: Client is not allowed to use this token";
+static UNKNOWN_ALIAS_MSG: &str = "http-proxy: Unknown token alias";
+static UNKNOWN_CACHE_KEY_MSG: &str = "http-proxy: No cached response for this key";
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Debug)]
@@ -19,34 +41,93 @@ pub enum RequestError {
     AcquiringTicket {
         source: Box<dyn Error + Send + Sync>,
     },
+    DeadlineExceeded,
+    Forbidden,
     InvalidMethod {
         method: Method,
     },
     InvalidPath {
         source: PathParseError,
     },
+    InvalidRequestLimit {
+        retry_after: Duration,
+    },
     InvalidURI {
         source: InvalidUri,
     },
+    MissingParameter {
+        name: &'static str,
+    },
+    NotFound,
+    ProxyAuthRequired,
+    QuarantinedToken {
+        retry_after: Duration,
+    },
+    QueueFull {
+        retry_after: Duration,
+    },
+    ReadOnly {
+        retry_after: Duration,
+    },
+    ReadingBody {
+        source: HyperError,
+    },
     RequestIssue {
         source: HyperError,
     },
+    TokenNotAllowed,
+    UnknownAlias {
+        alias: String,
+    },
+    UnknownCacheKey,
 }
 
 impl RequestError {
     pub fn as_response(&self) -> Response<Body> {
         let (status_code, body) = match self {
             RequestError::AcquiringTicket { .. } => (500, ACQUIRING_TICKET_FAILED_MSG),
+            RequestError::DeadlineExceeded => (504, DEADLINE_EXCEEDED_MSG),
+            RequestError::Forbidden => (403, FORBIDDEN_MSG),
             RequestError::InvalidURI { .. } => (500, INVALID_URI_MSG),
             RequestError::InvalidMethod { .. } => (501, INVALID_METHOD_MSG),
             RequestError::InvalidPath { .. } => (501, INVALID_PATH_MSG),
+            RequestError::InvalidRequestLimit { .. } => (503, INVALID_REQUEST_LIMIT_MSG),
+            RequestError::MissingParameter { .. } => (400, MISSING_PARAMETER_MSG),
+            RequestError::NotFound => (404, NOT_FOUND_MSG),
+            RequestError::ProxyAuthRequired => (407, PROXY_AUTH_REQUIRED_MSG),
+            RequestError::QuarantinedToken { .. } => (401, QUARANTINED_TOKEN_MSG),
+            RequestError::QueueFull { .. } => (503, QUEUE_FULL_MSG),
+            RequestError::ReadOnly { .. } => (503, READ_ONLY_MSG),
+            RequestError::ReadingBody { .. } => (400, READING_BODY_MSG),
             RequestError::RequestIssue { .. } => (502, REQUEST_ISSUE_MSG),
+            RequestError::TokenNotAllowed => (401, TOKEN_NOT_ALLOWED_MSG),
+            RequestError::UnknownAlias { .. } => (401, UNKNOWN_ALIAS_MSG),
+            RequestError::UnknownCacheKey => (404, UNKNOWN_CACHE_KEY_MSG),
         };
 
-        Response::builder()
-            .status(status_code)
-            .body(Body::from(body))
-            .unwrap()
+        let mut builder = Response::builder().status(status_code);
+
+        if let Some(retry_after) = self.retry_after() {
+            // Round up so that clients don't retry too early
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            builder = builder.header(RETRY_AFTER, secs);
+        }
+
+        if let RequestError::ProxyAuthRequired = self {
+            builder = builder.header(PROXY_AUTHENTICATE, "Basic realm=\"twilight-http-proxy\"");
+        }
+
+        builder.body(Body::from(body)).unwrap()
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            RequestError::InvalidRequestLimit { retry_after }
+            | RequestError::QuarantinedToken { retry_after }
+            | RequestError::QueueFull { retry_after }
+            | RequestError::ReadOnly { retry_after } => Some(*retry_after),
+            _ => None,
+        }
     }
 }
 
@@ -57,6 +138,8 @@ impl Display for RequestError {
                 f.write_str("error when acquiring ratelimiting ticket: ")?;
                 source.fmt(f)
             }
+            Self::DeadlineExceeded => f.write_str("deadline passed before a ticket was available"),
+            Self::Forbidden => f.write_str("request is forbidden"),
             Self::InvalidMethod { method } => {
                 f.write_str("invalid method: ")?;
                 method.fmt(f)
@@ -65,14 +148,44 @@ impl Display for RequestError {
                 f.write_str("invalid path: ")?;
                 source.fmt(f)
             }
+            Self::InvalidRequestLimit { retry_after } => {
+                write!(
+                    f,
+                    "invalid request limit reached, retry after {:?}",
+                    retry_after
+                )
+            }
             Self::InvalidURI { source } => {
                 f.write_str("generated uri for discord api is invalid: ")?;
                 source.fmt(f)
             }
+            Self::MissingParameter { name } => {
+                f.write_str("missing query parameter: ")?;
+                f.write_str(name)
+            }
+            Self::NotFound => f.write_str("unknown admin endpoint"),
+            Self::ProxyAuthRequired => f.write_str("missing or invalid proxy credentials"),
+            Self::QuarantinedToken { retry_after } => {
+                write!(f, "token is quarantined for another {:?}", retry_after)
+            }
+            Self::QueueFull { retry_after } => {
+                write!(f, "ratelimit queue is full, retry after {:?}", retry_after)
+            }
+            Self::ReadOnly { .. } => f.write_str("proxy is read-only"),
+            Self::ReadingBody { source } => {
+                f.write_str("error reading request body: ")?;
+                source.fmt(f)
+            }
             Self::RequestIssue { source } => {
                 f.write_str("error executing request: ")?;
                 source.fmt(f)
             }
+            Self::TokenNotAllowed => f.write_str("client is not allowed to use this token"),
+            Self::UnknownAlias { alias } => {
+                f.write_str("unknown token alias: ")?;
+                f.write_str(alias)
+            }
+            Self::UnknownCacheKey => f.write_str("no cached response for this key"),
         }
     }
 }
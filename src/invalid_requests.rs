@@ -0,0 +1,171 @@
+use crate::config;
+use dashmap::DashMap;
+use http::StatusCode;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use tokio::time::{interval, Duration, Instant};
+use tracing::{debug, warn};
+
+#[cfg(feature = "expose-metrics")]
+use lazy_static::lazy_static;
+#[cfg(feature = "expose-metrics")]
+use metrics::gauge;
+
+#[cfg(feature = "expose-metrics")]
+lazy_static! {
+    static ref METRIC_KEY_INVALID_REQUESTS: String =
+        format!("{}_invalid_requests", config::current().metrics.key);
+}
+
+/// Timestamps of invalid responses within the sliding window.
+#[derive(Default)]
+struct Window(VecDeque<Instant>);
+
+impl Window {
+    fn expire(&mut self, now: Instant, window: Duration) {
+        while let Some(oldest) = self.0.front() {
+            if now - *oldest < window {
+                break;
+            }
+
+            self.0.pop_front();
+        }
+    }
+
+    fn record(&mut self, now: Instant, window: Duration) -> usize {
+        self.expire(now, window);
+        self.0.push_back(now);
+
+        self.0.len()
+    }
+
+    /// How long until fewer than `limit` invalid responses are left in the
+    /// window, or `None` if that is already the case.
+    fn blocked_for(&mut self, now: Instant, window: Duration, limit: u32) -> Option<Duration> {
+        self.expire(now, window);
+
+        let excess = self.0.len().checked_sub(limit as usize)?;
+        let unblocked_at = *self.0.get(excess)? + window;
+
+        Some(unblocked_at - now)
+    }
+}
+
+/// Tracks responses that count towards Discord's invalid request limit, which
+/// bans the IP of anyone sending too many of them.
+///
+/// <https://discord.com/developers/docs/topics/rate-limits#invalid-request-limit-aka-cloudflare-bans>
+pub struct InvalidRequests {
+    global: Mutex<Window>,
+    tokens: DashMap<String, Window>,
+}
+
+impl InvalidRequests {
+    pub fn new() -> Arc<Self> {
+        let invalid_requests = Arc::new(Self {
+            global: Mutex::default(),
+            tokens: DashMap::new(),
+        });
+
+        tokio::spawn(reaper(invalid_requests.clone()));
+
+        invalid_requests
+    }
+
+    /// Whether a response with this status counts as an invalid request.
+    ///
+    /// 429s with a shared scope are excluded by Discord.
+    pub fn is_invalid(status: StatusCode, scope: Option<&str>) -> bool {
+        match status.as_u16() {
+            401 | 403 => true,
+            429 => scope != Some("shared"),
+            _ => false,
+        }
+    }
+
+    pub fn record(&self, token: &str) {
+        let config = config::current();
+        let window = Duration::from_secs(config.invalid_requests.window);
+        let now = Instant::now();
+
+        let global = self
+            .global
+            .lock()
+            .expect("invalid requests got poisoned")
+            .record(now, window);
+        let for_token = self
+            .tokens
+            .entry(token.to_owned())
+            .or_default()
+            .record(now, window);
+
+        for threshold in &config.invalid_requests.warn_at {
+            if global == *threshold as usize {
+                warn!(
+                    "{} invalid requests in the last {:?}, Discord bans IPs at 10000",
+                    global, window
+                );
+            }
+
+            if for_token == *threshold as usize {
+                warn!(
+                    "{} invalid requests for a single token in the last {:?}",
+                    for_token, window
+                );
+            }
+        }
+    }
+
+    /// Returns how long requests have to be refused for, if the global or
+    /// per-token limit was reached.
+    pub fn blocked_for(&self, token: &str) -> Option<Duration> {
+        let config = config::current();
+        let window = Duration::from_secs(config.invalid_requests.window);
+        let now = Instant::now();
+
+        let global = self
+            .global
+            .lock()
+            .expect("invalid requests got poisoned")
+            .blocked_for(now, window, config.invalid_requests.limit);
+
+        let for_token = config.invalid_requests.token_limit.and_then(|limit| {
+            self.tokens
+                .get_mut(token)
+                .and_then(|mut entry| entry.blocked_for(now, window, limit))
+        });
+
+        global.max(for_token)
+    }
+}
+
+async fn reaper(invalid_requests: Arc<InvalidRequests>) {
+    let mut interval = interval(Duration::from_secs(60));
+
+    loop {
+        interval.tick().await;
+
+        let window = Duration::from_secs(config::current().invalid_requests.window);
+        let now = Instant::now();
+
+        invalid_requests.tokens.retain(|_, entry| {
+            entry.expire(now, window);
+
+            !entry.0.is_empty()
+        });
+
+        #[cfg(feature = "expose-metrics")]
+        {
+            let mut global = invalid_requests
+                .global
+                .lock()
+                .expect("invalid requests got poisoned");
+            global.expire(now, window);
+            gauge!(METRIC_KEY_INVALID_REQUESTS.as_str(), global.0.len() as f64);
+        }
+
+        debug!("Done reaping expired invalid requests");
+    }
+}
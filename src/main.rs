@@ -1,11 +1,32 @@
+mod admin;
+mod auth;
 mod cache;
+mod coalesce;
+mod conditional;
+mod config;
 mod error;
+mod invalid_requests;
+mod policy;
+mod queues;
+mod ratelimit_snapshot;
 mod ratelimiter_map;
-
+mod read_only;
+mod redis_connection;
+mod redis_ratelimiter;
+mod retry;
+mod token_quarantine;
+mod upstream;
+mod verified_tokens;
+
+use coalesce::{Flight, InFlight};
+use conditional::Conditions;
 use error::RequestError;
 use http::{
-    header::{AUTHORIZATION, CONNECTION, HOST, TRANSFER_ENCODING, UPGRADE},
-    HeaderValue, Method as HttpMethod, Uri,
+    header::{
+        AUTHORIZATION, CONNECTION, HOST, IF_MODIFIED_SINCE, IF_NONE_MATCH, PROXY_AUTHORIZATION,
+        TRANSFER_ENCODING, UPGRADE,
+    },
+    HeaderMap, HeaderValue, Method as HttpMethod, StatusCode,
 };
 use hyper::{
     body::{to_bytes, Body},
@@ -14,24 +35,57 @@ use hyper::{
 };
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use hyper_trust_dns::{TrustDnsHttpConnector, TrustDnsResolver};
+use invalid_requests::InvalidRequests;
+use queues::Queues;
 use ratelimiter_map::RatelimiterMap;
+use read_only::ReadOnly;
+use retry::Replay;
 use std::{
     convert::{Infallible, TryFrom},
-    env,
     error::Error,
-    net::{IpAddr, SocketAddr},
-    ops::Not,
-    str::FromStr,
+    future::Future,
+    net::SocketAddr,
     sync::Arc,
+    time::Duration,
+};
+use token_quarantine::TokenQuarantine;
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    time::{self, sleep},
 };
 use tracing::{debug, error, info, trace, warn};
 use tracing_subscriber::EnvFilter;
-use twilight_http_ratelimiting::{
-    InMemoryRatelimiter, Method, Path, RatelimitHeaders, Ratelimiter,
-};
+use twilight_http_ratelimiting::{Method, Path, RatelimitHeaders, Ratelimiter};
+use upstream::Upstream;
+use verified_tokens::VerifiedTokens;
+
+use crate::config::Config;
+
+/// Marks requests that the proxy makes by itself to refresh a stale cached
+/// response.
+#[derive(Clone, Copy)]
+struct Revalidating;
+
+/// A request refreshing a stale cached response in the background.
+struct Revalidation {
+    ratelimiter: Arc<dyn Ratelimiter>,
+    token: String,
+    client: Option<String>,
+    request: Request<Body>,
+}
+
+/// Response header with the amount of times the proxy retried a ratelimited
+/// request.
+static PROXY_RETRIES: &str = "x-proxy-retries";
+
+/// Request header with the amount of milliseconds a request may wait for a
+/// ratelimit ticket before the proxy gives up on it.
+static PROXY_DEADLINE: &str = "x-proxy-deadline";
 
 #[cfg(unix)]
 use tokio::signal::unix::{signal, SignalKind};
+#[cfg(unix)]
+use tracing_subscriber::{fmt::Formatter, reload::Handle};
 
 #[cfg(feature = "expose-metrics")]
 use std::time::Instant;
@@ -44,28 +98,26 @@ use metrics::{decrement_gauge, histogram, increment_gauge};
 use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
 #[cfg(feature = "expose-metrics")]
 use metrics_util::MetricKindMask;
-#[cfg(feature = "expose-metrics")]
-use std::time::Duration;
 
-use crate::cache::Cache;
+use crate::{
+    cache::{Cache, CachedResponse},
+    config::CachePartition,
+};
 
 #[cfg(feature = "expose-metrics")]
 lazy_static! {
-    static ref METRIC_KEY: String =
-        env::var("METRIC_KEY").unwrap_or_else(|_| "twilight_http_proxy".into());
+    static ref METRIC_KEY: String = config::current().metrics.key.clone();
 }
 
 #[cfg(feature = "expose-metrics")]
 lazy_static! {
-    static ref METRIC_KEY_IN_PROGRESS: String = format!(
-        "{}_in_progress",
-        env::var("METRIC_KEY").unwrap_or_else(|_| "twilight_http_proxy".into())
-    );
+    static ref METRIC_KEY_IN_PROGRESS: String =
+        format!("{}_in_progress", config::current().metrics.key);
 }
 
 #[cfg(feature = "expose-metrics")]
 lazy_static! {
-    static ref TRACK_IN_PROGRESS: bool = parse_env("TRACK_IN_PROGRESS").unwrap_or(false);
+    static ref TRACK_IN_PROGRESS: bool = config::current().metrics.track_in_progress;
 }
 
 #[cfg(feature = "expose-metrics")]
@@ -92,28 +144,53 @@ impl Drop for InProgressGuard {
     }
 }
 
+/// State shared by all connections.
+struct State {
+    client: Client<HttpsConnector<TrustDnsHttpConnector>, Body>,
+    ratelimiter_map: RatelimiterMap,
+    cache: Arc<Cache>,
+    upstream: Upstream,
+    invalid_requests: Arc<InvalidRequests>,
+    token_quarantine: TokenQuarantine,
+    verified_tokens: VerifiedTokens,
+    queues: Queues,
+    read_only: ReadOnly,
+    in_flight: InFlight,
+    revalidations: UnboundedSender<Revalidation>,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    tracing_subscriber::fmt()
+    let subscriber = tracing_subscriber::fmt()
         .with_env_filter(
             EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
         )
-        .init();
+        .with_filter_reloading();
+    let log_filter = subscriber.reload_handle();
+    subscriber.init();
 
-    let host_raw = env::var("HOST").unwrap_or_else(|_| "0.0.0.0".into());
-    let host = IpAddr::from_str(&host_raw)?;
-    let port = env::var("PORT").unwrap_or_else(|_| "80".into()).parse()?;
+    let config = Config::load()?;
+    info!("Effective configuration: {:#?}", config);
+    log_filter.reload(EnvFilter::new(&config.log_filter))?;
+    config::set(config);
+    let config = config::current();
 
     let https_connector = {
         let mut http_connector = TrustDnsResolver::default().into_http_connector();
         http_connector.enforce_http(false);
 
-        let builder = HttpsConnectorBuilder::new()
-            .with_webpki_roots()
-            .https_only()
-            .enable_http1();
+        let builder = HttpsConnectorBuilder::new().with_webpki_roots();
 
-        if env::var("DISABLE_HTTP2").is_ok() {
+        // Plain HTTP is only allowed if the upstream was explicitly configured
+        // to use it, e.g. for a local mock of the Discord API
+        let builder = if config.upstream_url.is_https() {
+            builder.https_only()
+        } else {
+            builder.https_or_http()
+        }
+        .enable_http1();
+
+        if config.disable_http2 {
             builder.wrap_connector(http_connector)
         } else {
             builder.enable_http2().wrap_connector(http_connector)
@@ -121,16 +198,15 @@ async fn main() -> Result<(), Box<dyn Error>> {
     };
 
     let client: Client<_, Body> = Client::builder().build(https_connector);
-    let ratelimiter_map = Arc::new(RatelimiterMap::new(env::var("DISCORD_TOKEN")?));
 
-    let address = SocketAddr::from((host, port));
+    let address = SocketAddr::from((config.host, config.port));
 
     #[cfg(feature = "expose-metrics")]
     let handle: Arc<PrometheusHandle>;
 
     #[cfg(feature = "expose-metrics")]
     {
-        let timeout = parse_env("METRIC_TIMEOUT").unwrap_or(300);
+        let timeout = config.metrics.timeout;
         let recorder = PrometheusBuilder::new()
             .idle_timeout(
                 MetricKindMask::COUNTER | MetricKindMask::HISTOGRAM,
@@ -142,43 +218,63 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .expect("Failed to create metrics receiver!");
     }
 
-    let cache = Cache::new();
+    let (revalidations, revalidation_receiver) = mpsc::unbounded_channel();
+    let state = Arc::new(State {
+        client,
+        ratelimiter_map: RatelimiterMap::new(config.discord_token.expose().to_owned()),
+        cache: Cache::new().await,
+        upstream: config.upstream_url.clone(),
+        invalid_requests: InvalidRequests::new(),
+        token_quarantine: TokenQuarantine::new(),
+        verified_tokens: VerifiedTokens::new(),
+        queues: Queues::new(),
+        read_only: ReadOnly::new(),
+        in_flight: InFlight::new(),
+        revalidations,
+    });
+    tokio::spawn(revalidation_worker(state.clone(), revalidation_receiver));
+    tokio::spawn(snapshot_worker(state.clone()));
+    let shutdown_state = state.clone();
+    #[cfg(unix)]
+    let reload_state = state.clone();
+    #[cfg(unix)]
+    let read_only_state = state.clone();
 
     // The closure inside `make_service_fn` is run for each connection,
     // creating a 'service' to handle requests for that specific connection.
     let service = service::make_service_fn(move |addr: &AddrStream| {
         trace!("Connection from: {:?}", addr);
-        let ratelimiter_map = ratelimiter_map.clone();
-        // Cloning a hyper client is fairly cheap by design
-        let client = client.clone();
+        let state = state.clone();
 
         #[cfg(feature = "expose-metrics")]
         let handle = handle.clone();
-        let cache = cache.clone();
 
         async move {
             Ok::<_, Infallible>(service::service_fn(move |incoming: Request<Body>| {
-                let token = incoming
-                    .headers()
-                    .get("authorization")
-                    .and_then(|value| value.to_str().ok());
-                let (ratelimiter, token) = ratelimiter_map.get_or_insert(token);
-                let client = client.clone();
+                let state = state.clone();
 
                 #[cfg(feature = "expose-metrics")]
                 let handle = handle.clone();
-                let cache = cache.clone();
 
                 async move {
+                    let auth = config::current().auth.clone();
+
                     Ok::<_, Infallible>({
                         match incoming.uri().path() {
                             #[cfg(feature = "expose-metrics")]
-                            "/metrics" => handle_metrics(handle),
-                            "/health" => handle_health(),
-                            _ => handle_request(client, ratelimiter, token, incoming, cache)
-                                .await
-                                .unwrap_or_else(|err| err.as_response()),
+                            "/metrics" if auth.public_metrics => Ok(handle_metrics(handle)),
+                            #[cfg(feature = "expose-metrics")]
+                            "/metrics" => auth::authenticate_client(incoming.headers())
+                                .map(|_| handle_metrics(handle)),
+                            "/health" if auth.public_health => Ok(handle_health()),
+                            "/health" => auth::authenticate_client(incoming.headers())
+                                .map(|_| handle_health()),
+                            path if path.starts_with("/admin/") => {
+                                admin::handle(state, incoming).await
+                            }
+                            _ => handle_proxied(state, incoming).await,
                         }
+                        .unwrap_or_else(|err| err.as_response())
                     })
                 }
             }))
@@ -187,14 +283,22 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let server = Server::bind(&address).serve(service);
 
+    #[cfg(unix)]
+    tokio::spawn(reload_signal(log_filter, reload_state));
+    #[cfg(unix)]
+    tokio::spawn(read_only_signal(read_only_state));
+
     let graceful = server.with_graceful_shutdown(shutdown_signal());
 
     info!("Listening on http://{}", address);
+    info!("Forwarding requests to {}", config.upstream_url);
 
     if let Err(why) = graceful.await {
         error!("Fatal server error: {}", why);
     }
 
+    shutdown_state.ratelimiter_map.save_snapshot().await;
+
     Ok(())
 }
 
@@ -216,6 +320,65 @@ async fn shutdown_signal() {
     };
 }
 
+/// Re-reads the configuration whenever SIGHUP is received and applies it to
+/// live state, without touching the server or in-flight requests.
+#[cfg(unix)]
+async fn reload_signal(log_filter: Handle<EnvFilter, Formatter>, state: Arc<State>) {
+    let mut sighup = signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
+
+    while sighup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading configuration");
+
+        let config = match config::reload() {
+            Ok(config) => config,
+            Err(e) => {
+                error!(
+                    "Failed to reload configuration, keeping the current one: {}",
+                    e
+                );
+                continue;
+            }
+        };
+
+        if let Err(e) = log_filter.reload(EnvFilter::new(&config.log_filter)) {
+            error!("Failed to apply reloaded log filter: {}", e);
+        }
+
+        state
+            .ratelimiter_map
+            .set_default_token(config.discord_token.expose().to_owned());
+
+        info!("Effective configuration: {:#?}", config);
+    }
+}
+
+/// Turns read-only mode on when SIGUSR1 is received and off on SIGUSR2.
+#[cfg(unix)]
+async fn read_only_signal(state: Arc<State>) {
+    let mut sigusr1 =
+        signal(SignalKind::user_defined1()).expect("failed to install SIGUSR1 handler");
+    let mut sigusr2 =
+        signal(SignalKind::user_defined2()).expect("failed to install SIGUSR2 handler");
+
+    loop {
+        tokio::select! {
+            _ = sigusr1.recv() => state.read_only.set(true, "SIGUSR1"),
+            _ = sigusr2.recv() => state.read_only.set(false, "SIGUSR2"),
+        };
+    }
+}
+
+/// Name of the `Path` variant, which identifies the route in the
+/// configuration.
+fn route_name(path: &Path) -> String {
+    let debug = format!("{:?}", path);
+
+    match debug.find('(') {
+        Some(index) => debug[..index].to_owned(),
+        None => debug,
+    }
+}
+
 fn path_name(path: &Path) -> &'static str {
     match path {
         Path::ApplicationCommand(..) => "Application commands",
@@ -313,15 +476,57 @@ fn normalize_path(request_path: &str) -> (&str, &str) {
     }
 }
 
+/// Authenticates the client and resolves the token to use before handing the
+/// request off to [`handle_request`].
+async fn handle_proxied(
+    state: Arc<State>,
+    incoming: Request<Body>,
+) -> Result<Response<Body>, RequestError> {
+    let client = auth::authenticate(incoming.headers())?;
+
+    let token = incoming
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok());
+    let token = state.ratelimiter_map.resolve(token)?;
+
+    if let Some(retry_after) = state.token_quarantine.remaining(&token) {
+        debug!("Refusing request for quarantined token");
+        return Err(RequestError::QuarantinedToken { retry_after });
+    }
+
+    let ratelimiter = state.ratelimiter_map.get_or_insert(&token);
+
+    if let Err(e) = auth::authorize_token(
+        client.as_ref(),
+        &token,
+        state.ratelimiter_map.is_default_token(&token),
+    ) {
+        debug!(
+            "Client {:?} is not allowed to use this token",
+            client.map(|client| client.name)
+        );
+        return Err(e);
+    }
+
+    let client = client.map(|client| client.name);
+    handle_request(state, ratelimiter, token, client, incoming).await
+}
+
 async fn handle_request(
-    client: Client<HttpsConnector<TrustDnsHttpConnector>, Body>,
-    ratelimiter: InMemoryRatelimiter,
+    state: Arc<State>,
+    ratelimiter: Arc<dyn Ratelimiter>,
     token: String,
+    client: Option<String>,
     mut request: Request<Body>,
-    cache: Arc<Cache>,
 ) -> Result<Response<Body>, RequestError> {
     trace!("Incoming request: {:?}", request);
 
+    let deadline = request
+        .headers_mut()
+        .remove(PROXY_DEADLINE)
+        .and_then(|deadline| parse_deadline(&deadline));
+
     let (method, m) = match *request.method() {
         HttpMethod::DELETE => (Method::Delete, "DELETE"),
         HttpMethod::GET => (Method::Get, "GET"),
@@ -336,6 +541,13 @@ async fn handle_request(
         }
     };
 
+    if method != Method::Get && state.read_only.is_enabled() {
+        debug!("Refusing {} request in read-only mode", m);
+        return Err(RequestError::ReadOnly {
+            retry_after: Duration::from_secs(config::current().read_only.retry_after),
+        });
+    }
+
     let request_path = request.uri().path().to_owned();
 
     let (api_path, trimmed_path) = normalize_path(&request_path);
@@ -351,48 +563,124 @@ async fn handle_request(
         }
     };
 
+    let route = route_name(&path);
+    let is_default_token = state.ratelimiter_map.is_default_token(&token);
+    if !policy::is_allowed(m, &route, &token, is_default_token, client.as_deref()) {
+        debug!(
+            "Policy denied {} {} for client {:?}",
+            m, trimmed_path, client
+        );
+        return Err(RequestError::Forbidden);
+    }
+
     let p = path_name(&path);
     #[cfg(feature = "expose-metrics")]
     let _guard = InProgressGuard::new(m, &p);
 
     let api_route = format!("{}{}", api_path, trimmed_path);
 
-    // check our cache for some paths
-    let cached_reply = match path {
-        Path::InvitesCode => cache.get_invite(&api_route),
-        Path::UsersId => api_route
-            .contains("@me")
-            .not()
-            .then(|| cache.get_user(&api_route))
-            .flatten(),
+    // Responses for the current user depend on the token and are only cached
+    // if the route is partitioned
+    let cacheable = match config::current().cache.route(&route) {
+        Some(cache_route)
+            if method == Method::Get
+                && (cache_route.partition != CachePartition::Shared
+                    || !api_route.contains("@me")) =>
+        {
+            let key = cache::key(
+                cache_route.partition,
+                &token,
+                state.verified_tokens.contains(&token),
+                &api_route,
+                request.uri().query(),
+            );
+
+            Some((cache_route, key))
+        }
         _ => None,
     };
 
-    if let Some((bytes, headers, statuscode)) = cached_reply {
-        debug!("{} {} ({}): {} from cache", m, p, request_path, statuscode);
-        let mut builder = Response::builder().status(statuscode);
-        for (name, value) in headers {
-            // no clue why this could ever be None, but just in case let's check it
-            if let Some(name) = name {
-                builder = builder.header(name, value)
-            }
+    // Background revalidations must not be answered by the stale response
+    // they are meant to replace
+    let is_revalidation = request.extensions().get::<Revalidating>().is_some();
+    let cache_config = config::current().cache.clone();
+    let conditions = Conditions::new(request.headers());
+
+    if let Some((_, key)) = cacheable.as_ref().filter(|_| !is_revalidation) {
+        let fresh = state
+            .cache
+            .get(key)
+            .await
+            .and_then(|cached| cached_response(&conditions, cached));
+
+        if let Some(response) = fresh {
+            debug!(
+                "{} {} ({}): {} from cache",
+                m,
+                p,
+                request_path,
+                response.status()
+            );
+            return Ok(response);
         }
-        match builder.body(Body::from(bytes)) {
-            Ok(response) => return Ok(response),
-            Err(e) => {
-                error!("Failed to re-assemble body: {}", e)
+
+        let stale_while_revalidate = Duration::from_secs(cache_config.stale_while_revalidate);
+        let stale = state
+            .cache
+            .get_stale(key, stale_while_revalidate)
+            .await
+            .and_then(|cached| cached_response(&conditions, cached));
+
+        if let Some(response) = stale {
+            if state.cache.start_revalidation(key) {
+                let mut revalidation = Request::new(Body::empty());
+                *revalidation.uri_mut() = request.uri().clone();
+                *revalidation.headers_mut() = request.headers().clone();
+                revalidation.headers_mut().remove(IF_MODIFIED_SINCE);
+                revalidation.headers_mut().remove(IF_NONE_MATCH);
+                revalidation.extensions_mut().insert(Revalidating);
+
+                let revalidation = Revalidation {
+                    ratelimiter: ratelimiter.clone(),
+                    token: token.clone(),
+                    client: client.clone(),
+                    request: revalidation,
+                };
+
+                if state.revalidations.send(revalidation).is_err() {
+                    error!("Revalidation worker stopped");
+                }
             }
-        };
-    }
 
-    let header_sender = match ratelimiter.wait_for_ticket(path.clone()).await {
-        Ok(sender) => sender,
-        Err(e) => {
-            error!("Failed to receive ticket for ratelimiting: {:?}", e);
-            return Err(RequestError::AcquiringTicket { source: e });
+            debug!(
+                "{} {} ({}): {} stale from cache",
+                m,
+                p,
+                request_path,
+                response.status()
+            );
+            return Ok(response);
         }
+    }
+
+    // Serves the stale response if Discord can't be reached or fails
+    let stale_key = cacheable
+        .as_ref()
+        .filter(|_| !is_revalidation)
+        .map(|(_, key)| key.clone());
+    let stale_if_error = Duration::from_secs(cache_config.stale_if_error);
+
+    // Expired responses with validators are revalidated with a conditional
+    // request, unless the client sent validators of its own
+    let revalidated = match &cacheable {
+        Some((_, key)) if conditions.is_empty() => state.cache.revalidatable(key).await,
+        _ => None,
     };
 
+    if let Some(cached) = &revalidated {
+        conditional::add_validators(request.headers_mut(), &cached.headers);
+    }
+
     request.headers_mut().insert(
         AUTHORIZATION,
         HeaderValue::from_bytes(token.as_bytes())
@@ -400,7 +688,11 @@ async fn handle_request(
     );
     request
         .headers_mut()
-        .insert(HOST, HeaderValue::from_static("discord.com"));
+        .insert(HOST, state.upstream.host_header());
+
+    // Don't leak the client's credentials for the proxy to Discord
+    request.headers_mut().remove(auth::PROXY_AUTH);
+    request.headers_mut().remove(PROXY_AUTHORIZATION);
 
     // Remove forbidden HTTP/2 headers
     // https://datatracker.ietf.org/doc/html/rfc7540#section-8.1.2.2
@@ -410,14 +702,7 @@ async fn handle_request(
     request.headers_mut().remove(TRANSFER_ENCODING);
     request.headers_mut().remove(UPGRADE);
 
-    let mut uri_string = format!("https://discord.com{}", api_route);
-
-    if let Some(query) = request.uri().query() {
-        uri_string.push('?');
-        uri_string.push_str(query);
-    }
-
-    let uri = match Uri::from_str(&uri_string) {
+    let uri = match state.upstream.uri(&api_route, request.uri().query()) {
         Ok(uri) => uri,
         Err(e) => {
             error!("Failed to create URI for requesting Discord API: {:?}", e);
@@ -426,102 +711,432 @@ async fn handle_request(
     };
     *request.uri_mut() = uri;
 
-    #[cfg(feature = "expose-metrics")]
-    let start = Instant::now();
+    let retries_config = config::current().retries.clone();
 
-    let resp = match client.request(request).await {
-        Ok(response) => response,
-        Err(e) => {
-            error!("Error when requesting the Discord API: {:?}", e);
-            return Err(RequestError::RequestIssue { source: e });
-        }
+    // The body has to be buffered if we may need to send the request again
+    let replay = if retries_config.enabled {
+        let (parts, body) = request.into_parts();
+        let body = match to_bytes(body).await {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Error when receiving request body from client: {:?}", e);
+                return Err(RequestError::ReadingBody { source: e });
+            }
+        };
+        let replay = Replay::new(parts, body);
+        request = replay.request();
+
+        Some(replay)
+    } else {
+        None
     };
 
-    let ratelimit_headers = RatelimitHeaders::from_pairs(
-        resp.headers()
-            .into_iter()
-            .map(|(k, v)| (k.as_str(), v.as_bytes())),
-    )
-    .ok();
+    // Identical GET requests that arrive while one is in flight wait for its
+    // response instead of taking a ticket of their own. Requests with
+    // validators of their own may get a `304 Not Modified`, which is no answer
+    // to the others, so they are sent on their own.
+    let coalesce = method == Method::Get && conditions.is_empty();
+    let flight = if coalesce && config::current().coalesce_requests {
+        let key = match &cacheable {
+            Some((_, key)) => key.clone(),
+            None => cache::key(
+                CachePartition::Token,
+                &token,
+                false,
+                &api_route,
+                request.uri().query(),
+            ),
+        };
+
+        match state.in_flight.join(key) {
+            Flight::Leader(leader) => Some(leader),
+            Flight::Follower(receiver) => {
+                let response = match before_deadline(deadline, coalesce::wait(receiver)).await {
+                    Some(response) => response,
+                    None => {
+                        warn!(
+                            "{} {} ({}): refused, deadline passed while coalesced",
+                            m, p, request_path
+                        );
+                        return Err(RequestError::DeadlineExceeded);
+                    }
+                };
+
+                if let Some(response) = response {
+                    debug!("{} {} ({}): coalesced", m, p, request_path);
+                    return Ok(response.response());
+                }
 
-    if header_sender.headers(ratelimit_headers).is_err() {
-        error!("Error when sending ratelimit headers to ratelimiter");
+                None
+            }
+        }
+    } else {
+        None
     };
 
-    #[cfg(feature = "expose-metrics")]
-    let end = Instant::now();
+    let mut retries = 0;
 
-    trace!("Response: {:?}", resp);
+    let mut resp = loop {
+        if let Some(retry_after) = state.invalid_requests.blocked_for(&token) {
+            warn!(
+                "{} {} ({}): refused, invalid request limit reached",
+                m, p, request_path
+            );
+            return Err(RequestError::InvalidRequestLimit { retry_after });
+        }
 
-    let status = resp.status();
-    #[cfg(feature = "expose-metrics")]
-    {
+        let queue_slot = match state.queues.enter(&token, &path) {
+            Ok(slot) => slot,
+            Err(queued) => {
+                warn!(
+                    "{} {} ({}): refused, {} requests are already queued",
+                    m, p, request_path, queued
+                );
+                let retry_after = queues::estimate_wait(&*ratelimiter, &path, queued).await;
+                return Err(RequestError::QueueFull { retry_after });
+            }
+        };
+
+        let ticket = ratelimiter.wait_for_ticket(path.clone());
+
+        // Dropping the ticket before it is handed out leaves it to the next
+        // request
+        let ticket = match before_deadline(deadline, ticket).await {
+            Some(ticket) => ticket,
+            None => {
+                warn!(
+                    "{} {} ({}): refused, deadline passed while queued",
+                    m, p, request_path
+                );
+                return Err(RequestError::DeadlineExceeded);
+            }
+        };
+
+        let header_sender = match ticket {
+            Ok(sender) => sender,
+            Err(e) => {
+                error!("Failed to receive ticket for ratelimiting: {:?}", e);
+                return Err(RequestError::AcquiringTicket { source: e });
+            }
+        };
+        drop(queue_slot);
+
+        #[cfg(feature = "expose-metrics")]
+        let start = Instant::now();
+
+        let resp = match state.client.request(request).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("Error when requesting the Discord API: {:?}", e);
+
+                if let Some(response) =
+                    stale_response(&state, stale_key.as_deref(), stale_if_error, &conditions).await
+                {
+                    debug!("{} {} ({}): stale from cache", m, p, request_path);
+                    return Ok(response);
+                }
+
+                return Err(RequestError::RequestIssue { source: e });
+            }
+        };
+
+        let ratelimit_headers = RatelimitHeaders::from_pairs(
+            resp.headers()
+                .into_iter()
+                .map(|(k, v)| (k.as_str(), v.as_bytes())),
+        )
+        .ok();
+
+        if header_sender.headers(ratelimit_headers).is_err() {
+            error!("Error when sending ratelimit headers to ratelimiter");
+        };
+
+        #[cfg(feature = "expose-metrics")]
+        let end = Instant::now();
+
+        trace!("Response: {:?}", resp);
+
+        let status = resp.status();
         let scope = resp
             .headers()
             .get("X-RateLimit-Scope")
-            .and_then(|header| header.to_str().ok())
-            .unwrap_or("")
-            .to_string();
-        histogram!(METRIC_KEY.as_str(), end - start, "method"=>m, "route"=>p, "status"=>status.to_string(), "scope" => scope);
-    }
+            .and_then(|header| header.to_str().ok());
 
-    debug!("{} {} ({}): {}", m, p, request_path, status);
+        if InvalidRequests::is_invalid(status, scope) {
+            state.invalid_requests.record(&token);
+        }
 
-    if resp.status().is_success() || resp.status() == 404 {
-        let (parts, body) = resp.into_parts();
-        return match to_bytes(body).await {
-            Ok(bytes) => {
-                let vec = bytes.to_vec();
-                let mut headers = parts.headers.clone();
-                headers.remove("x-ratelimit-bucket");
-                headers.remove("x-ratelimit-remaining");
-                headers.remove("x-ratelimit-reset");
-                headers.remove("x-ratelimit-reset-after");
-
-                match path {
-                    Path::InvitesCode => cache.insert_invite(api_route, vec, headers, parts.status),
-                    Path::UsersId => {
-                        api_route
-                            .contains("@me")
-                            .not()
-                            .then(|| cache.insert_user(api_route, vec, headers, parts.status));
-                    }
-                    _ => {}
+        // Webhook tokens in the path are accepted no matter the token in the
+        // header
+        if status.is_success() && !token_quarantine::has_own_token(&path) {
+            state.verified_tokens.insert(&token);
+        }
+
+        #[cfg(feature = "expose-metrics")]
+        {
+            let scope = scope.unwrap_or("").to_string();
+            histogram!(METRIC_KEY.as_str(), end - start, "method"=>m, "route"=>p, "status"=>status.to_string(), "scope" => scope);
+        }
+
+        // Routes such as webhooks carry their own token in the path, which is
+        // what a 401 for them is about
+        let resp = if status == 401 && !token_quarantine::has_own_token(&path) {
+            let (parts, body) = resp.into_parts();
+            let bytes = match to_bytes(body).await {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    error!("Error when receiving request body from discord: {:?}", e);
+                    return Err(RequestError::RequestIssue { source: e });
                 }
-                Ok(Response::from_parts(parts, Body::from(bytes)))
+            };
+
+            if token_quarantine::is_token_rejection(&bytes) {
+                state.token_quarantine.insert(&token);
             }
+
+            Response::from_parts(parts, Body::from(bytes))
+        } else {
+            resp
+        };
+
+        debug!("{} {} ({}): {}", m, p, request_path, status);
+
+        let replay = match &replay {
+            Some(replay) if status == 429 && retries < retries_config.max_retries => replay,
+            _ => break resp,
+        };
+
+        let (parts, body) = resp.into_parts();
+        let bytes = match to_bytes(body).await {
+            Ok(bytes) => bytes,
             Err(e) => {
                 error!("Error when receiving request body from discord: {:?}", e);
-                Err(RequestError::RequestIssue { source: e })
+                return Err(RequestError::RequestIssue { source: e });
             }
         };
+
+        let max_wait = Duration::from_secs(retries_config.max_wait);
+        match retry::retry_after(&parts.headers, &bytes).filter(|wait| *wait <= max_wait) {
+            Some(wait) => {
+                retries += 1;
+                debug!(
+                    "{} {} ({}): retrying in {:?} ({}/{})",
+                    m, p, request_path, wait, retries, retries_config.max_retries
+                );
+
+                sleep(wait).await;
+                request = replay.request();
+            }
+            None => break Response::from_parts(parts, Body::from(bytes)),
+        }
+    };
+
+    if replay.is_some() {
+        resp.headers_mut()
+            .insert(PROXY_RETRIES, HeaderValue::from(retries));
+    }
+
+    // Discord confirmed that the expired response is still current, so it is
+    // served and cached again as if it was fetched
+    if let Some(mut cached) = revalidated.filter(|_| resp.status() == StatusCode::NOT_MODIFIED) {
+        debug!(
+            "{} {} ({}): revalidated cached response",
+            m, p, request_path
+        );
+        conditional::merge_headers(&mut cached.headers, resp.headers());
+
+        let mut response = Response::new(Body::from(cached.bytes));
+        *response.status_mut() = cached.statuscode;
+        *response.headers_mut() = cached.headers;
+        resp = response;
+    }
+
+    if resp.status().is_server_error() {
+        if let Some(response) =
+            stale_response(&state, stale_key.as_deref(), stale_if_error, &conditions).await
+        {
+            debug!(
+                "{} {} ({}): {}, stale from cache",
+                m,
+                p,
+                request_path,
+                resp.status()
+            );
+            return Ok(response);
+        }
+    }
+
+    if method != Method::Get && resp.status().is_success() {
+        let routes = config::current().cache.invalidated_routes(&route);
+        state.cache.invalidate(trimmed_path, &routes).await;
+    }
+
+    let ttl = cacheable.as_ref().and_then(|(cache_route, _)| {
+        if resp.status().is_success() {
+            cache_route.ttl
+        } else if resp.status() == StatusCode::NOT_FOUND {
+            cache_route.negative_ttl
+        } else {
+            None
+        }
+    });
+
+    // Responses are only buffered to be shared if another request waits for
+    // them
+    let flight = flight.filter(|leader| leader.has_followers());
+
+    if ttl.is_none() && flight.is_none() {
+        return Ok(resp);
+    }
+
+    let (parts, body) = resp.into_parts();
+    let bytes = match to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("Error when receiving request body from discord: {:?}", e);
+            return Err(RequestError::RequestIssue { source: e });
+        }
     };
 
-    Ok(resp)
+    if let Some(leader) = flight {
+        leader.complete(&parts, &bytes);
+    }
+
+    if let (Some(ttl), Some((_, key))) = (ttl, cacheable) {
+        let mut headers = parts.headers.clone();
+        headers.remove("x-ratelimit-bucket");
+        headers.remove("x-ratelimit-remaining");
+        headers.remove("x-ratelimit-reset");
+        headers.remove("x-ratelimit-reset-after");
+        headers.remove(PROXY_RETRIES);
+
+        let cached = CachedResponse::new(
+            route,
+            trimmed_path.to_owned(),
+            ttl,
+            bytes.to_vec(),
+            headers,
+            parts.status,
+        );
+        state.cache.insert(key, cached).await;
+    }
+
+    Ok(Response::from_parts(parts, Body::from(bytes)))
 }
 
-#[cfg(feature = "expose-metrics")]
-fn handle_metrics(handle: Arc<PrometheusHandle>) -> Response<Body> {
-    Response::builder()
-        .body(Body::from(handle.render()))
-        .unwrap()
+/// Returns the cached response if it expired no more than `max_staleness` ago.
+async fn stale_response(
+    state: &State,
+    key: Option<&str>,
+    max_staleness: Duration,
+    conditions: &Conditions,
+) -> Option<Response<Body>> {
+    state
+        .cache
+        .get_stale(key?, max_staleness)
+        .await
+        .and_then(|cached| cached_response(conditions, cached))
 }
 
-pub fn parse_env<T: FromStr>(key: &str) -> Option<T> {
-    env::var_os(key).and_then(|value| match value.into_string() {
-        Ok(s) => {
-            if let Ok(t) = s.parse() {
-                Some(t)
-            } else {
-                warn!("Unable to parse {}, proceeding with defaults", key);
-                None
-            }
+/// Rebuilds a response from the cache, or a `304 Not Modified` if the client
+/// already has it.
+fn cached_response(
+    conditions: &Conditions,
+    (bytes, headers, statuscode): (Vec<u8>, HeaderMap<HeaderValue>, StatusCode),
+) -> Option<Response<Body>> {
+    if statuscode.is_success() && conditions.matches(&headers) {
+        return Some(conditional::not_modified(&headers));
+    }
+
+    let mut builder = Response::builder().status(statuscode);
+    for (name, value) in headers {
+        // no clue why this could ever be None, but just in case let's check it
+        if let Some(name) = name {
+            builder = builder.header(name, value)
         }
-        Err(s) => {
-            warn!("{} is not UTF-8: {:?}", key, s);
+    }
+
+    match builder.body(Body::from(bytes)) {
+        Ok(response) => Some(response),
+        Err(e) => {
+            error!("Failed to re-assemble body: {}", e);
             None
         }
-    })
+    }
+}
+
+/// Returns when a request with this `X-Proxy-Deadline` header must have
+/// received its ticket or coalesced response.
+fn parse_deadline(value: &HeaderValue) -> Option<time::Instant> {
+    let millis = match value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+    {
+        Some(millis) => millis,
+        None => {
+            debug!("Ignoring invalid {} header {:?}", PROXY_DEADLINE, value);
+            return None;
+        }
+    };
+
+    // Deadlines too far ahead to be represented are as good as none
+    time::Instant::now().checked_add(Duration::from_millis(millis))
+}
+
+/// Waits for `future`, or returns `None` if the deadline passes first.
+async fn before_deadline<F: Future>(
+    deadline: Option<time::Instant>,
+    future: F,
+) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Refreshes stale cached responses in the background.
+///
+/// Revalidations are handed over through a channel because `handle_request`
+/// can't spawn itself.
+async fn revalidation_worker(state: Arc<State>, mut receiver: UnboundedReceiver<Revalidation>) {
+    while let Some(revalidation) = receiver.recv().await {
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let path = revalidation.request.uri().path().to_owned();
+            let result = handle_request(
+                state,
+                revalidation.ratelimiter,
+                revalidation.token,
+                revalidation.client,
+                revalidation.request,
+            )
+            .await;
+
+            if let Err(e) = result {
+                warn!("Failed to revalidate cached response for {}: {}", path, e);
+            }
+        });
+    }
+}
+
+/// Saves ratelimit buckets periodically, so that they also survive crashes.
+async fn snapshot_worker(state: Arc<State>) {
+    loop {
+        // Re-read on every iteration so that reloaded settings apply
+        let interval = config::current().clients.snapshot_interval;
+        sleep(Duration::from_secs(interval)).await;
+
+        state.ratelimiter_map.save_snapshot().await;
+    }
+}
+
+#[cfg(feature = "expose-metrics")]
+fn handle_metrics(handle: Arc<PrometheusHandle>) -> Response<Body> {
+    Response::builder()
+        .body(Body::from(handle.render()))
+        .unwrap()
 }
 
 fn handle_health() -> Response<Body> {
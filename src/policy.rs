@@ -0,0 +1,49 @@
+use crate::{
+    auth,
+    config::{self, PolicyAction, PolicyRule},
+};
+
+/// Whether any of the values matches, or `true` if the criterion is not set.
+fn any_matches<T>(criterion: &Option<Vec<T>>, f: impl Fn(&T) -> bool) -> bool {
+    match criterion {
+        Some(values) => values.iter().any(f),
+        None => true,
+    }
+}
+
+fn rule_matches(
+    rule: &PolicyRule,
+    method: &str,
+    route: &str,
+    token: &str,
+    is_default: bool,
+    client: Option<&str>,
+) -> bool {
+    any_matches(&rule.methods, |m| m.eq_ignore_ascii_case(method))
+        && any_matches(&rule.routes, |r| r == route)
+        && any_matches(&rule.tokens, |allowed| {
+            auth::token_matches(allowed.expose(), token, is_default)
+        })
+        && any_matches(&rule.clients, |c| Some(c.as_str()) == client)
+}
+
+/// Whether the configured policy allows the request. The first matching rule
+/// decides, falling back to the default action.
+pub fn is_allowed(
+    method: &str,
+    route: &str,
+    token: &str,
+    is_default: bool,
+    client: Option<&str>,
+) -> bool {
+    let config = config::current();
+
+    let action = config
+        .policy
+        .rules
+        .iter()
+        .find(|rule| rule_matches(rule, method, route, token, is_default, client))
+        .map_or(config.policy.default_action, |rule| rule.action);
+
+    action == PolicyAction::Allow
+}
@@ -0,0 +1,113 @@
+use dashmap::DashMap;
+use std::{convert::TryFrom, hash::Hash, sync::Arc};
+use tokio::time::Duration;
+use twilight_http_ratelimiting::{request::Path, Ratelimiter};
+
+use crate::config;
+
+/// Counts the requests waiting for a ratelimit ticket, so that a client
+/// sending faster than its ratelimits allow can't pile up requests without
+/// bound.
+pub struct Queues {
+    buckets: Arc<DashMap<(String, Path), usize>>,
+    tokens: Arc<DashMap<String, usize>>,
+}
+
+/// A request waiting for a ticket, which leaves the queues when dropped.
+pub struct QueueSlot {
+    bucket: (String, Path),
+    buckets: Arc<DashMap<(String, Path), usize>>,
+    tokens: Arc<DashMap<String, usize>>,
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        leave(&self.buckets, &self.bucket);
+        leave(&self.tokens, &self.bucket.0);
+    }
+}
+
+/// Decrements a count, removing it once no requests are left.
+fn leave<K: Eq + Hash>(map: &DashMap<K, usize>, key: &K) {
+    if let Some(mut count) = map.get_mut(key) {
+        *count -= 1;
+    }
+
+    map.remove_if(key, |_, count| *count == 0);
+}
+
+/// Increments a count unless it reached the maximum, returning the count
+/// before.
+fn enter<K: Eq + Hash>(
+    map: &DashMap<K, usize>,
+    key: K,
+    max: Option<usize>,
+) -> Result<usize, usize> {
+    let mut count = map.entry(key).or_default();
+
+    if matches!(max, Some(max) if *count >= max) {
+        return Err(*count);
+    }
+
+    *count += 1;
+
+    Ok(*count - 1)
+}
+
+impl Queues {
+    pub fn new() -> Self {
+        Self {
+            buckets: Arc::new(DashMap::new()),
+            tokens: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Adds a request to the queues of its bucket and token, unless either is
+    /// full. Otherwise, returns how many requests are waiting for the bucket.
+    pub fn enter(&self, token: &str, path: &Path) -> Result<QueueSlot, usize> {
+        let config = &config::current().queues;
+        let bucket = (token.to_owned(), path.clone());
+
+        let queued = enter(&self.buckets, bucket.clone(), config.max_per_bucket)?;
+
+        if enter(&self.tokens, token.to_owned(), config.max_per_token).is_err() {
+            leave(&self.buckets, &bucket);
+            return Err(queued);
+        }
+
+        Ok(QueueSlot {
+            bucket,
+            buckets: self.buckets.clone(),
+            tokens: self.tokens.clone(),
+        })
+    }
+}
+
+/// Estimates how long it takes until a request queued behind `queued` others
+/// would get a ticket for its bucket.
+pub async fn estimate_wait(ratelimiter: &dyn Ratelimiter, path: &Path, queued: usize) -> Duration {
+    let bucket = match ratelimiter.bucket(path).await {
+        Ok(Some(bucket)) => bucket,
+        _ => return Duration::from_secs(1),
+    };
+
+    // Buckets without ratelimit headers yet have an infinite limit and reset
+    // time, which would overflow
+    if bucket.limit() == u64::MAX {
+        return Duration::from_secs(1);
+    }
+
+    let reset_in = bucket.time_remaining().unwrap_or_default();
+    let windows = match bucket.limit() {
+        0 => 0,
+        limit => queued as u64 / limit,
+    };
+
+    let wait = u32::try_from(windows)
+        .ok()
+        .and_then(|windows| bucket.reset_after().checked_mul(windows))
+        .and_then(|windows| windows.checked_add(reset_in))
+        .unwrap_or(reset_in);
+
+    wait.max(Duration::from_secs(1))
+}
@@ -0,0 +1,207 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::Path as FilePath,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::time::sleep;
+use twilight_http_ratelimiting::{
+    request::Path, Bucket, GetBucketFuture, GetTicketFuture, HasBucketFuture, InMemoryRatelimiter,
+    IsGloballyLockedFuture, Ratelimiter,
+};
+
+/// State of a bucket whose reset was still ahead when it was saved.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SavedBucket {
+    /// Hash of the token the bucket belongs to.
+    pub token: String,
+    /// Debug representation of the `twilight_http_ratelimiting::Path`.
+    pub path: String,
+    pub limit: u64,
+    pub remaining: u64,
+    pub reset_at: SystemTime,
+}
+
+/// Reads the buckets saved in a snapshot file.
+pub fn load(file: &FilePath) -> io::Result<Vec<SavedBucket>> {
+    let bytes = fs::read(file)?;
+
+    serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Replaces the snapshot file with the given buckets.
+pub async fn save(file: &FilePath, buckets: &[SavedBucket]) -> io::Result<()> {
+    let bytes = serde_json::to_vec(buckets).expect("saved buckets are serializable");
+
+    // Write to a temporary file first, so that the snapshot is never partial
+    let mut temporary = file.as_os_str().to_owned();
+    temporary.push(".tmp");
+
+    tokio::fs::write(&temporary, bytes).await?;
+    tokio::fs::rename(&temporary, file).await
+}
+
+/// In-memory ratelimiter whose buckets can be saved, which holds back requests
+/// for restored buckets that had no tickets left until they reset.
+#[derive(Debug)]
+pub struct PersistentRatelimiter {
+    inner: InMemoryRatelimiter,
+    /// Hash of the token.
+    token: String,
+    /// Paths tickets were handed out for, whose buckets may need saving.
+    paths: Mutex<HashSet<Path>>,
+    /// Restored buckets by path, until the first ticket for them is handed out
+    /// after they had tickets left or reset.
+    restored: Mutex<HashMap<String, SavedBucket>>,
+}
+
+impl PersistentRatelimiter {
+    pub fn new(token: String, restored: Vec<SavedBucket>) -> Self {
+        Self {
+            inner: InMemoryRatelimiter::new(),
+            token,
+            paths: Mutex::default(),
+            restored: Mutex::new(
+                restored
+                    .into_iter()
+                    .map(|bucket| (bucket.path.clone(), bucket))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Returns how long a request must wait for its restored bucket to reset.
+    ///
+    /// Once a restored bucket has tickets left or has reset, the in-memory
+    /// ratelimiter learns its state from the next response instead.
+    fn restored_wait(&self, path: &Path) -> Option<Duration> {
+        let key = format!("{:?}", path);
+        let mut restored = self.restored.lock().expect("restored buckets poisoned");
+        let bucket = restored.get(&key)?;
+
+        match bucket.reset_at.duration_since(SystemTime::now()) {
+            Ok(wait) if bucket.remaining == 0 => Some(wait),
+            _ => {
+                restored.remove(&key);
+                None
+            }
+        }
+    }
+
+    /// Returns the state of all buckets that have yet to reset.
+    pub async fn snapshot(&self) -> Vec<SavedBucket> {
+        let now = SystemTime::now();
+        let mut buckets: Vec<SavedBucket> = self
+            .restored
+            .lock()
+            .expect("restored buckets poisoned")
+            .values()
+            .filter(|bucket| bucket.reset_at > now)
+            .cloned()
+            .collect();
+
+        let paths: Vec<Path> = self
+            .paths
+            .lock()
+            .expect("bucket paths poisoned")
+            .iter()
+            .cloned()
+            .collect();
+
+        for path in paths {
+            let bucket = match self.inner.bucket(&path).await {
+                Ok(Some(bucket)) => bucket,
+                // The bucket was dropped after not being used for a while
+                _ => {
+                    self.paths
+                        .lock()
+                        .expect("bucket paths poisoned")
+                        .remove(&path);
+                    continue;
+                }
+            };
+
+            // Buckets without ratelimit headers yet have an infinite reset
+            // time, which would overflow
+            if bucket.limit() == u64::MAX {
+                continue;
+            }
+
+            if let Some(reset_in) = bucket.time_remaining() {
+                let path = format!("{:?}", path);
+                buckets.retain(|restored| restored.path != path);
+                buckets.push(SavedBucket {
+                    token: self.token.clone(),
+                    path,
+                    limit: bucket.limit(),
+                    remaining: bucket.remaining(),
+                    reset_at: now + reset_in,
+                });
+            }
+        }
+
+        buckets
+    }
+}
+
+impl Ratelimiter for PersistentRatelimiter {
+    fn bucket(&self, path: &Path) -> GetBucketFuture {
+        let restored = self
+            .restored
+            .lock()
+            .expect("restored buckets poisoned")
+            .get(&format!("{:?}", path))
+            .and_then(|bucket| {
+                let reset_in = bucket.reset_at.duration_since(SystemTime::now()).ok()?;
+
+                Some(Bucket::new(
+                    bucket.limit,
+                    bucket.remaining,
+                    reset_in,
+                    Some(Instant::now()),
+                ))
+            });
+
+        match restored {
+            Some(bucket) => Box::pin(async move { Ok(Some(bucket)) }),
+            None => self.inner.bucket(path),
+        }
+    }
+
+    fn is_globally_locked(&self) -> IsGloballyLockedFuture {
+        self.inner.is_globally_locked()
+    }
+
+    fn has(&self, path: &Path) -> HasBucketFuture {
+        if self
+            .restored
+            .lock()
+            .expect("restored buckets poisoned")
+            .contains_key(&format!("{:?}", path))
+        {
+            return Box::pin(async { Ok(true) });
+        }
+
+        self.inner.has(path)
+    }
+
+    fn ticket(&self, path: Path) -> GetTicketFuture {
+        let wait = self.restored_wait(&path);
+        self.paths
+            .lock()
+            .expect("bucket paths poisoned")
+            .insert(path.clone());
+
+        let inner = self.inner.clone();
+
+        Box::pin(async move {
+            if let Some(wait) = wait {
+                sleep(wait).await;
+            }
+
+            inner.ticket(path).await
+        })
+    }
+}
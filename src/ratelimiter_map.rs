@@ -1,29 +1,43 @@
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
 use dashmap::{mapref::multiple::RefMulti, DashMap};
-use std::sync::Arc;
-use tokio::time::{interval, Duration, Instant};
-use tracing::debug;
-use twilight_http_ratelimiting::InMemoryRatelimiter;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::Path,
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::SystemTime,
+};
+use tokio::time::{sleep, Duration, Instant};
+use tracing::{debug, error, info, warn};
+use twilight_http_ratelimiting::{InMemoryRatelimiter, Ratelimiter};
 
-use crate::parse_env;
+use crate::{
+    config,
+    config::RatelimiterKind,
+    error::RequestError,
+    ratelimit_snapshot::{self, PersistentRatelimiter, SavedBucket},
+    redis_ratelimiter::RedisRatelimiter,
+};
+
+/// Ratelimiter of a token and when it was last used.
+type Entry = (Arc<dyn Ratelimiter>, Instant);
 
 pub struct RatelimiterMap {
-    default: InMemoryRatelimiter,
-    default_token: String,
-    max_size: Option<usize>,
-    inner: Arc<DashMap<String, (InMemoryRatelimiter, Instant)>>,
+    backend: Backend,
+    default: RwLock<(Arc<dyn Ratelimiter>, String)>,
+    inner: Arc<DashMap<String, Entry>>,
 }
 
-async fn reap_old_ratelimiters(map: Arc<DashMap<String, (InMemoryRatelimiter, Instant)>>) {
-    let client_reap_interval =
-        Duration::from_secs(parse_env("CLIENT_REAP_INTERVAL").unwrap_or(600));
-
-    let client_decay_timeout =
-        Duration::from_secs(parse_env("CLIENT_DECAY_TIMEOUT").unwrap_or(3600));
-
-    let mut interval = interval(client_reap_interval);
-
+async fn reap_old_ratelimiters(map: Arc<DashMap<String, Entry>>) {
     loop {
-        interval.tick().await;
+        // Re-read on every iteration so that reloaded settings apply
+        let config = config::current();
+        let client_reap_interval = Duration::from_secs(config.clients.reap_interval);
+        let client_decay_timeout = Duration::from_secs(config.clients.decay_timeout);
+        drop(config);
+
+        sleep(client_reap_interval).await;
         let right_now = Instant::now();
 
         map.retain(|_, (_, last_used)| *last_used + client_decay_timeout > right_now);
@@ -32,33 +46,244 @@ async fn reap_old_ratelimiters(map: Arc<DashMap<String, (InMemoryRatelimiter, In
     }
 }
 
-impl RatelimiterMap {
-    pub fn new(mut default_token: String) -> Self {
-        let is_bot = default_token.starts_with("Bot ");
-        let is_bearer = default_token.starts_with("Bearer ");
-
-        // Make sure it is either a bot or bearer token, and assume it's a bot
-        // token if no prefix is given
-        if !is_bot && !is_bearer {
-            default_token.insert_str(0, "Bot ");
+/// Creates the ratelimiters of tokens.
+struct Backend {
+    /// Ratelimiter the ratelimiters of tokens are created from, if they are
+    /// tracked on Redis.
+    redis: Option<RedisRatelimiter>,
+    /// Whether in-memory ratelimiters are saved to the snapshot file.
+    snapshots: bool,
+    /// Buckets restored from the snapshot file by token hash, until a
+    /// ratelimiter is created for the token.
+    restored: Mutex<HashMap<String, Vec<SavedBucket>>>,
+    /// In-memory ratelimiters, whose buckets are saved to the snapshot file.
+    persistent: Mutex<Vec<Weak<PersistentRatelimiter>>>,
+}
+
+impl Backend {
+    fn new() -> Self {
+        let config = config::current();
+
+        let redis = match config.clients.ratelimiter {
+            RatelimiterKind::Redis => Some(RedisRatelimiter::connect(&config.redis)),
+            RatelimiterKind::Memory => None,
+        };
+
+        // Buckets on Redis outlive the proxy by themselves
+        let snapshots = redis.is_none() && config.clients.snapshot_file.is_some();
+        let restored = match &config.clients.snapshot_file {
+            Some(file) if snapshots => restore(file),
+            _ => HashMap::new(),
+        };
+
+        Self {
+            redis,
+            snapshots,
+            restored: Mutex::new(restored),
+            persistent: Mutex::default(),
+        }
+    }
+
+    fn create(&self, token: &str) -> Arc<dyn Ratelimiter> {
+        if let Some(redis) = &self.redis {
+            return Arc::new(redis.for_token(token));
         }
 
-        let max_size = parse_env("CLIENT_CACHE_MAX_SIZE");
+        if !self.snapshots {
+            return Arc::new(InMemoryRatelimiter::new());
+        }
+
+        let token = hash_token(token);
+        let restored = self
+            .restored
+            .lock()
+            .expect("restored buckets poisoned")
+            .remove(&token)
+            .unwrap_or_default();
+
+        let ratelimiter = Arc::new(PersistentRatelimiter::new(token, restored));
+        let mut persistent = self
+            .persistent
+            .lock()
+            .expect("persistent ratelimiters poisoned");
+
+        // Ratelimiters are created for every request if they aren't kept
+        persistent.retain(|ratelimiter| ratelimiter.strong_count() > 0);
+        persistent.push(Arc::downgrade(&ratelimiter));
+        drop(persistent);
+
+        ratelimiter
+    }
+}
+
+/// Reads the buckets that have yet to reset from the snapshot file, by token
+/// hash.
+fn restore(file: &Path) -> HashMap<String, Vec<SavedBucket>> {
+    let buckets = match ratelimit_snapshot::load(file) {
+        Ok(buckets) => buckets,
+        Err(e) if e.kind() == ErrorKind::NotFound => return HashMap::new(),
+        Err(e) => {
+            warn!(
+                "Failed to restore ratelimit buckets from {}: {}",
+                file.display(),
+                e
+            );
+            return HashMap::new();
+        }
+    };
+
+    let now = SystemTime::now();
+    let mut restored: HashMap<String, Vec<SavedBucket>> = HashMap::new();
+    let mut count = 0;
+
+    for bucket in buckets.into_iter().filter(|bucket| bucket.reset_at > now) {
+        restored
+            .entry(bucket.token.clone())
+            .or_default()
+            .push(bucket);
+        count += 1;
+    }
+
+    info!(
+        "Restored {} ratelimit buckets from {}",
+        count,
+        file.display()
+    );
+
+    restored
+}
+
+pub fn normalize_token(mut token: String) -> String {
+    let is_bot = token.starts_with("Bot ");
+    let is_bearer = token.starts_with("Bearer ");
+
+    // Make sure it is either a bot or bearer token, and assume it's a bot
+    // token if no prefix is given
+    if !is_bot && !is_bearer {
+        token.insert_str(0, "Bot ");
+    }
+
+    token
+}
+
+/// Returns a stable, non-reversible identifier for the token, for use where
+/// tokens would otherwise be stored or compared in plain text.
+pub fn hash_token(token: &str) -> String {
+    let digest = Sha256::digest(normalize_token(token.to_owned()).as_bytes());
+
+    digest[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Returns the ID of the application a bot token belongs to, which is encoded
+/// in its first segment.
+pub fn application_id(token: &str) -> Option<u64> {
+    let token = token.strip_prefix("Bot ")?;
+    let encoded = token.split('.').next()?.trim_end_matches('=');
+    let decoded = STANDARD_NO_PAD.decode(encoded).ok()?;
 
+    String::from_utf8(decoded).ok()?.parse().ok()
+}
+
+impl RatelimiterMap {
+    pub fn new(default_token: String) -> Self {
         let inner = Arc::new(DashMap::new());
-        let default = InMemoryRatelimiter::new();
+        let backend = Backend::new();
 
         tokio::spawn(reap_old_ratelimiters(inner.clone()));
 
+        let default_token = normalize_token(default_token);
+        let default = backend.create(&default_token);
+
         Self {
-            default,
-            default_token,
-            max_size,
+            backend,
+            default: RwLock::new((default, default_token)),
             inner,
         }
     }
 
-    fn lru(&self) -> Option<RefMulti<String, (InMemoryRatelimiter, Instant)>> {
+    /// Saves the buckets of in-memory ratelimiters that have yet to reset to
+    /// `clients.snapshot_file`, if set.
+    pub async fn save_snapshot(&self) {
+        let file = match config::current().clients.snapshot_file.clone() {
+            Some(file) => file,
+            None => return,
+        };
+
+        if !self.backend.snapshots {
+            return;
+        }
+
+        // Ratelimiters that were dropped no longer have buckets worth saving
+        let ratelimiters: Vec<Arc<PersistentRatelimiter>> = {
+            let mut persistent = self
+                .backend
+                .persistent
+                .lock()
+                .expect("persistent ratelimiters poisoned");
+            persistent.retain(|ratelimiter| ratelimiter.strong_count() > 0);
+
+            persistent.iter().filter_map(Weak::upgrade).collect()
+        };
+
+        let mut buckets = Vec::new();
+        for ratelimiter in ratelimiters {
+            buckets.extend(ratelimiter.snapshot().await);
+        }
+
+        // Restored buckets of tokens that weren't used since are kept too
+        buckets.extend(
+            self.backend
+                .restored
+                .lock()
+                .expect("restored buckets poisoned")
+                .values()
+                .flatten()
+                .filter(|bucket| bucket.reset_at > SystemTime::now())
+                .cloned(),
+        );
+
+        match ratelimit_snapshot::save(&file, &buckets).await {
+            Ok(()) => debug!(
+                "Saved {} ratelimit buckets to {}",
+                buckets.len(),
+                file.display()
+            ),
+            Err(e) => error!(
+                "Failed to save ratelimit buckets to {}: {}",
+                file.display(),
+                e
+            ),
+        }
+    }
+
+    /// Replaces the token used for requests without an `Authorization` header.
+    ///
+    /// Requests that are already queued keep using the previous token.
+    pub fn set_default_token(&self, token: String) {
+        let token = normalize_token(token);
+        let mut default = self.default.write().expect("default ratelimiter poisoned");
+
+        if default.1 != token {
+            *default = (self.backend.create(&token), token);
+            info!("Replaced default token");
+        }
+    }
+
+    pub fn is_default_token(&self, token: &str) -> bool {
+        self.default.read().expect("default ratelimiter poisoned").1 == token
+    }
+
+    fn default(&self) -> (Arc<dyn Ratelimiter>, String) {
+        self.default
+            .read()
+            .expect("default ratelimiter poisoned")
+            .clone()
+    }
+
+    fn lru(&self) -> Option<RefMulti<String, Entry>> {
         self.inner.iter().next().map(|first_entry| {
             self.inner.iter().fold(
                 first_entry,
@@ -73,44 +298,64 @@ impl RatelimiterMap {
         })
     }
 
-    pub fn get_or_insert(&self, token: Option<&str>) -> (InMemoryRatelimiter, String) {
-        if let Some(token) = token {
-            if token == self.default_token {
-                (self.default.clone(), self.default_token.clone())
-            } else {
-                let access_time = Instant::now();
-
-                if let Some(mut entry) = self.inner.get_mut(token) {
-                    entry.1 = access_time;
-                    (entry.0.clone(), token.to_string())
-                } else {
-                    if self
-                        .max_size
-                        .filter(|max_size| self.inner.len() >= *max_size && max_size > &0)
-                        .is_some()
-                    {
-                        let key = self
-                            .lru()
-                            .expect("Length of inner map is guaranteed to be greater than 0")
-                            .key()
-                            .clone();
-
-                        self.inner.remove(&key);
-                        debug!("Removed oldest entry from HTTP ratelimiter cache");
-                    }
+    /// Returns the ratelimiter and the token to use for the given
+    /// `Authorization` header, resolving token aliases from the vault.
+    /// Returns the token to use for a request, resolving aliases and falling
+    /// back to the default token if none was given.
+    pub fn resolve(&self, token: Option<&str>) -> Result<String, RequestError> {
+        match token {
+            Some(token) => match token.strip_prefix("Alias ") {
+                Some(alias) => match config::current().vault.aliases.get(alias.trim()) {
+                    Some(token) => Ok(normalize_token(token.expose().to_owned())),
+                    None => Err(RequestError::UnknownAlias {
+                        alias: alias.trim().to_owned(),
+                    }),
+                },
+                None => Ok(token.to_owned()),
+            },
+            None => Ok(self.default().1),
+        }
+    }
 
-                    let ratelimiter = InMemoryRatelimiter::new();
+    /// Returns the ratelimiter of a token returned by [`Self::resolve`].
+    pub fn get_or_insert(&self, token: &str) -> Arc<dyn Ratelimiter> {
+        let (default, default_token) = self.default();
 
-                    if self.max_size.map_or(true, |max| max != 0) {
-                        self.inner
-                            .insert(token.to_string(), (ratelimiter.clone(), access_time));
-                    }
+        if token == default_token {
+            return default;
+        }
 
-                    (ratelimiter, token.to_string())
-                }
-            }
-        } else {
-            (self.default.clone(), self.default_token.clone())
+        let access_time = Instant::now();
+
+        if let Some(mut entry) = self.inner.get_mut(token) {
+            entry.1 = access_time;
+            return entry.0.clone();
+        }
+
+        let max_size = config::current().clients.cache_max_size;
+
+        // Loop as the maximum size may have shrunk since the last insert
+        while max_size
+            .filter(|max_size| self.inner.len() >= *max_size && max_size > &0)
+            .is_some()
+        {
+            let key = self
+                .lru()
+                .expect("Length of inner map is guaranteed to be greater than 0")
+                .key()
+                .clone();
+
+            self.inner.remove(&key);
+            debug!("Removed oldest entry from HTTP ratelimiter cache");
         }
+
+        let ratelimiter = self.backend.create(token);
+
+        if max_size.map_or(true, |max| max != 0) {
+            self.inner
+                .insert(token.to_string(), (ratelimiter.clone(), access_time));
+        }
+
+        ratelimiter
     }
 }
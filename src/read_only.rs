@@ -0,0 +1,29 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{info, warn};
+
+use crate::config;
+
+/// Runtime switch that makes the proxy refuse every request that isn't a GET,
+/// to freeze writes during an incident without stopping reads.
+pub struct ReadOnly(AtomicBool);
+
+impl ReadOnly {
+    pub fn new() -> Self {
+        Self(AtomicBool::new(config::current().read_only.enabled))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Turns read-only mode on or off, logging who did so.
+    pub fn set(&self, enabled: bool, source: &str) {
+        let was_enabled = self.0.swap(enabled, Ordering::Relaxed);
+
+        match (was_enabled, enabled) {
+            (false, true) => warn!("Read-only mode enabled by {}", source),
+            (true, false) => info!("Read-only mode disabled by {}", source),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use redis::{aio::ConnectionManager, ErrorKind, RedisResult};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{sync::OnceCell, time::sleep};
+use tracing::{error, info, warn};
+
+use crate::config::RedisConfig;
+
+/// Time between attempts to connect to the server, in seconds.
+const RETRY_INTERVAL: u64 = 5;
+
+/// Connection to a Redis-compatible server that is established in the
+/// background, so that the proxy starts even if the server is unreachable.
+///
+/// Once connected, the connection manager reconnects by itself.
+#[derive(Clone)]
+pub struct RedisConnection {
+    manager: Arc<OnceCell<ConnectionManager>>,
+    /// Whether the last command succeeded, used to only log changes.
+    reachable: Arc<AtomicBool>,
+    /// What the server is used for, and done in memory while it is
+    /// unreachable, such as "caching".
+    usage: &'static str,
+}
+
+impl RedisConnection {
+    /// Starts connecting to the server, retrying until it succeeds.
+    pub fn connect(config: &RedisConfig, usage: &'static str) -> Self {
+        let connection = Self {
+            manager: Arc::default(),
+            reachable: Arc::new(AtomicBool::new(true)),
+            usage,
+        };
+        tokio::spawn(connection.clone().establish(config.clone()));
+
+        connection
+    }
+
+    async fn establish(self, config: RedisConfig) {
+        let mut failed = false;
+
+        loop {
+            match config.connect().await {
+                Ok(manager) => {
+                    if failed {
+                        info!("Connected to Redis");
+                    }
+
+                    let _ = self.manager.set(manager);
+
+                    return;
+                }
+                Err(e) => {
+                    if !failed {
+                        error!(
+                            "Failed to connect to Redis, retrying every {}s: {}",
+                            RETRY_INTERVAL, e
+                        );
+                        failed = true;
+                    }
+
+                    sleep(Duration::from_secs(RETRY_INTERVAL)).await;
+                }
+            }
+        }
+    }
+
+    /// Returns the connection, if it was established.
+    pub fn get(&self) -> Option<ConnectionManager> {
+        let manager = self
+            .manager
+            .get()
+            .cloned()
+            .ok_or_else(|| (ErrorKind::IoError, "not connected to Redis yet").into());
+
+        self.check(manager)
+    }
+
+    /// Records whether a command succeeded, returning its result if it did.
+    pub fn check<T>(&self, result: RedisResult<T>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                if !self.reachable.swap(true, Ordering::Relaxed) {
+                    info!("Redis is reachable again, {} there", self.usage);
+                }
+
+                Some(value)
+            }
+            Err(e) => {
+                if self.reachable.swap(false, Ordering::Relaxed) {
+                    warn!(
+                        "Redis is unreachable, {} in memory instead: {}",
+                        self.usage, e
+                    );
+                }
+
+                None
+            }
+        }
+    }
+}
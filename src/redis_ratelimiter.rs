@@ -0,0 +1,506 @@
+use lazy_static::lazy_static;
+use redis::{AsyncCommands, RedisResult, Script};
+use std::{
+    fmt::{Debug, Formatter, Result as FmtResult},
+    time::{Duration, Instant},
+};
+use tokio::time::{sleep, timeout};
+use tracing::debug;
+use twilight_http_ratelimiting::{
+    request::Path,
+    ticket::{self, TicketNotifier},
+    Bucket, GetBucketFuture, GetTicketFuture, HasBucketFuture, InMemoryRatelimiter,
+    IsGloballyLockedFuture, RatelimitHeaders, Ratelimiter,
+};
+
+use crate::{config::RedisConfig, ratelimiter_map::hash_token, redis_connection::RedisConnection};
+
+/// Time to wait for the response headers of a request, in milliseconds. Until
+/// the first response of a bucket arrives, no other request may be sent for it.
+const WAIT: u64 = 10_000;
+
+/// Interval at which requests waiting for the first response of a bucket check
+/// whether it arrived, in milliseconds.
+const POLL_INTERVAL: u64 = 100;
+
+lazy_static! {
+    /// Takes a ticket for a bucket, returning how long to wait before trying
+    /// again or 0 if the request may be sent.
+    ///
+    /// Buckets are hashes of `limit`, `remaining`, `reset_at` and
+    /// `reset_after`. Times are taken from the server, so that the clocks of
+    /// the replicas don't matter.
+    static ref ACQUIRE: Script = Script::new(
+        r"
+        local time = redis.call('TIME')
+        local now = time[1] * 1000 + math.floor(time[2] / 1000)
+
+        local locked = redis.call('PTTL', KEYS[2])
+        if locked > 0 then
+            return locked
+        end
+
+        local bucket = redis.call('HMGET', KEYS[1], 'limit', 'remaining', 'reset_at', 'reset_after')
+        local limit = tonumber(bucket[1])
+        local remaining = tonumber(bucket[2])
+        local reset_at = tonumber(bucket[3])
+        local reset_after = tonumber(bucket[4])
+        local wait = tonumber(ARGV[1])
+
+        -- Until the limits are known, a single request is sent to learn them
+        if not limit then
+            if reset_at and reset_at > now then
+                return math.min(reset_at - now, tonumber(ARGV[2]))
+            end
+
+            redis.call('HSET', KEYS[1], 'remaining', 0, 'reset_at', now + wait)
+            redis.call('PEXPIRE', KEYS[1], wait)
+            return 0
+        end
+
+        if reset_at <= now then
+            remaining = limit
+            reset_at = now + reset_after
+            redis.call('HSET', KEYS[1], 'reset_at', reset_at)
+            redis.call('PEXPIRE', KEYS[1], reset_after + wait)
+        end
+
+        if remaining > 0 then
+            redis.call('HSET', KEYS[1], 'remaining', remaining - 1)
+            return 0
+        end
+
+        return reset_at - now
+        ",
+    );
+
+    /// Updates a bucket with the ratelimit headers of a response.
+    static ref UPDATE: Script = Script::new(
+        r"
+        local time = redis.call('TIME')
+        local now = time[1] * 1000 + math.floor(time[2] / 1000)
+
+        local limit = tonumber(ARGV[1])
+        local remaining = tonumber(ARGV[2])
+        local reset_after = tonumber(ARGV[3])
+
+        local bucket = redis.call('HMGET', KEYS[1], 'limit', 'remaining', 'reset_at')
+        local known_remaining = tonumber(bucket[2])
+        local known_reset_at = tonumber(bucket[3])
+
+        -- Responses from the same window may arrive out of order, and tickets
+        -- taken since were already subtracted, so the lowest count wins
+        if bucket[1] and known_remaining and known_reset_at and known_reset_at > now then
+            remaining = math.min(remaining, known_remaining)
+        end
+
+        redis.call('HSET', KEYS[1], 'limit', limit, 'remaining', remaining,
+            'reset_at', now + reset_after, 'reset_after', reset_after)
+        redis.call('PEXPIRE', KEYS[1], reset_after + tonumber(ARGV[4]))
+        return remaining
+        ",
+    );
+
+    /// Returns a ticket that didn't result in a response with ratelimit
+    /// headers. If the limits of the bucket are still unknown, the next request
+    /// may learn them right away, and if the ticket wasn't used, it is given
+    /// back.
+    static ref RELEASE: Script = Script::new(
+        r"
+        local bucket = redis.call('HMGET', KEYS[1], 'limit', 'remaining')
+
+        if not bucket[1] then
+            redis.call('DEL', KEYS[1])
+        elseif ARGV[1] == '1' and tonumber(bucket[2]) < tonumber(bucket[1]) then
+            redis.call('HINCRBY', KEYS[1], 'remaining', 1)
+        end
+
+        return 0
+        ",
+    );
+
+    /// Returns the limit and remaining tickets of a bucket and the time until
+    /// it resets.
+    static ref INSPECT: Script = Script::new(
+        r"
+        local time = redis.call('TIME')
+        local now = time[1] * 1000 + math.floor(time[2] / 1000)
+
+        local bucket = redis.call('HMGET', KEYS[1], 'limit', 'remaining', 'reset_at')
+        if not bucket[1] then
+            return nil
+        end
+
+        return {tonumber(bucket[1]), tonumber(bucket[2]), math.max(tonumber(bucket[3]) - now, 0)}
+        ",
+    );
+}
+
+/// Tracks ratelimit buckets on a Redis-compatible server, so that proxy
+/// replicas sharing a token don't exceed its limits together.
+///
+/// While the server is unreachable, tickets are handed out by an in-memory
+/// ratelimiter instead.
+#[derive(Clone)]
+pub struct RedisRatelimiter {
+    connection: RedisConnection,
+    /// Prepended to the keys of all buckets of the token.
+    prefix: String,
+    fallback: InMemoryRatelimiter,
+}
+
+impl Debug for RedisRatelimiter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("RedisRatelimiter")
+            .field("prefix", &self.prefix)
+            .field("fallback", &self.fallback)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisRatelimiter {
+    /// Starts connecting to the server. The returned ratelimiter only serves
+    /// to create those of tokens with [`RedisRatelimiter::for_token`].
+    pub fn connect(config: &RedisConfig) -> Self {
+        Self {
+            connection: RedisConnection::connect(config, "ratelimiting"),
+            prefix: format!("{}ratelimit:", config.key_prefix),
+            fallback: InMemoryRatelimiter::new(),
+        }
+    }
+
+    /// Returns a ratelimiter for the buckets of a token.
+    pub fn for_token(&self, token: &str) -> Self {
+        Self {
+            connection: self.connection.clone(),
+            prefix: format!("{}{}:", self.prefix, hash_token(token)),
+            fallback: InMemoryRatelimiter::new(),
+        }
+    }
+
+    fn bucket_key(&self, path: &Path) -> String {
+        format!("{}bucket:{:?}", self.prefix, path)
+    }
+
+    fn global_key(&self) -> String {
+        format!("{}global", self.prefix)
+    }
+
+    /// Waits until the request may be sent, then updates the bucket with the
+    /// headers of its response.
+    async fn queue(self, path: Path, notifier: TicketNotifier) {
+        let key = self.bucket_key(&path);
+        let mut connection = match self.connection.get() {
+            Some(connection) => connection,
+            None => return self.fall_back(path, notifier).await,
+        };
+
+        loop {
+            let result = ACQUIRE
+                .key(&key)
+                .key(self.global_key())
+                .arg(WAIT)
+                .arg(POLL_INTERVAL)
+                .invoke_async(&mut connection)
+                .await;
+
+            match self.connection.check(result) {
+                Some(0) => break,
+                Some(wait) => {
+                    debug!("Waiting {}ms for ratelimit of {:?} to pass", wait, path);
+                    sleep(Duration::from_millis(wait)).await;
+                }
+                None => return self.fall_back(path, notifier).await,
+            }
+        }
+
+        let headers = match notifier.available() {
+            Some(headers) => headers,
+            None => return self.release(&key, true).await,
+        };
+
+        match timeout(Duration::from_millis(WAIT), headers).await {
+            Ok(Ok(Some(headers))) => self.update(&key, headers).await,
+            _ => self.release(&key, false).await,
+        }
+    }
+
+    async fn update(&self, key: &str, headers: RatelimitHeaders) {
+        let mut connection = match self.connection.get() {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        match headers {
+            RatelimitHeaders::Global(global) => {
+                debug!("Request got globally ratelimited");
+                let retry_after = (global.retry_after() * 1000).max(1);
+                let result: RedisResult<()> =
+                    connection.pset_ex(self.global_key(), 1, retry_after).await;
+
+                self.connection.check(result);
+                self.release(key, false).await;
+            }
+            RatelimitHeaders::Present(present) => {
+                let result: RedisResult<u64> = UPDATE
+                    .key(key)
+                    .arg(present.limit())
+                    .arg(present.remaining())
+                    .arg(present.reset_after())
+                    .arg(WAIT)
+                    .invoke_async(&mut connection)
+                    .await;
+
+                self.connection.check(result);
+            }
+            _ => self.release(key, false).await,
+        }
+    }
+
+    /// Returns a ticket, which is given back if `unused`.
+    async fn release(&self, key: &str, unused: bool) {
+        let mut connection = match self.connection.get() {
+            Some(connection) => connection,
+            None => return,
+        };
+        let result: RedisResult<u64> = RELEASE
+            .key(key)
+            .arg(if unused { "1" } else { "0" })
+            .invoke_async(&mut connection)
+            .await;
+
+        self.connection.check(result);
+    }
+
+    /// Hands out the ticket with the in-memory ratelimiter instead.
+    async fn fall_back(&self, path: Path, notifier: TicketNotifier) {
+        let sender = match self.fallback.wait_for_ticket(path).await {
+            Ok(sender) => sender,
+            Err(_) => return,
+        };
+
+        if let Some(headers) = notifier.available() {
+            let headers = timeout(Duration::from_millis(WAIT), headers).await;
+            let _ = sender.headers(headers.ok().and_then(Result::ok).flatten());
+        }
+    }
+}
+
+impl Ratelimiter for RedisRatelimiter {
+    fn bucket(&self, path: &Path) -> GetBucketFuture {
+        let ratelimiter = self.clone();
+        let key = self.bucket_key(path);
+
+        Box::pin(async move {
+            let mut connection = match ratelimiter.connection.get() {
+                Some(connection) => connection,
+                None => return Ok(None),
+            };
+            let result: RedisResult<Option<(u64, u64, u64)>> =
+                INSPECT.key(key).invoke_async(&mut connection).await;
+
+            Ok(ratelimiter.connection.check(result).flatten().map(
+                |(limit, remaining, reset_after)| {
+                    Bucket::new(
+                        limit,
+                        remaining,
+                        Duration::from_millis(reset_after),
+                        Some(Instant::now()),
+                    )
+                },
+            ))
+        })
+    }
+
+    fn is_globally_locked(&self) -> IsGloballyLockedFuture {
+        let ratelimiter = self.clone();
+
+        Box::pin(async move {
+            let mut connection = match ratelimiter.connection.get() {
+                Some(connection) => connection,
+                None => return Ok(false),
+            };
+            let result = connection.exists(ratelimiter.global_key()).await;
+
+            Ok(ratelimiter.connection.check(result).unwrap_or_default())
+        })
+    }
+
+    fn has(&self, path: &Path) -> HasBucketFuture {
+        let ratelimiter = self.clone();
+        let key = self.bucket_key(path);
+
+        Box::pin(async move {
+            let mut connection = match ratelimiter.connection.get() {
+                Some(connection) => connection,
+                None => return Ok(false),
+            };
+            let result = connection.exists(key).await;
+
+            Ok(ratelimiter.connection.check(result).unwrap_or_default())
+        })
+    }
+
+    fn ticket(&self, path: Path) -> GetTicketFuture {
+        let (notifier, receiver) = ticket::channel();
+        tokio::spawn(self.clone().queue(path, notifier));
+
+        Box::pin(async move { Ok(receiver) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! The scripts are run on the Redis-compatible server at `TEST_REDIS_URL`
+    //! (defaults to `redis://127.0.0.1:6379`), such as a local `redis-server`,
+    //! with `cargo test -- --ignored`.
+
+    use super::{ACQUIRE, POLL_INTERVAL, RELEASE, UPDATE, WAIT};
+    use crate::config::RedisConfig;
+    use redis::{aio::ConnectionManager, AsyncCommands};
+    use std::{
+        env,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    struct Server {
+        connection: ConnectionManager,
+        bucket: String,
+        global: String,
+    }
+
+    impl Server {
+        /// Connects to the server, using keys that no other test uses.
+        async fn connect() -> Self {
+            let url =
+                env::var("TEST_REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".into());
+            let config = RedisConfig {
+                url: Some(url.parse().unwrap()),
+                ..RedisConfig::default()
+            };
+            let id = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos();
+
+            Self {
+                connection: config.connect().await.expect("test server is reachable"),
+                bucket: format!("http-proxy-test:{}:bucket", id),
+                global: format!("http-proxy-test:{}:global", id),
+            }
+        }
+
+        async fn acquire(&mut self) -> u64 {
+            ACQUIRE
+                .key(&self.bucket)
+                .key(&self.global)
+                .arg(WAIT)
+                .arg(POLL_INTERVAL)
+                .invoke_async(&mut self.connection)
+                .await
+                .unwrap()
+        }
+
+        async fn update(&mut self, limit: u64, remaining: u64, reset_after: u64) -> u64 {
+            UPDATE
+                .key(&self.bucket)
+                .arg(limit)
+                .arg(remaining)
+                .arg(reset_after)
+                .arg(WAIT)
+                .invoke_async(&mut self.connection)
+                .await
+                .unwrap()
+        }
+
+        async fn release(&mut self, unused: bool) {
+            let _: u64 = RELEASE
+                .key(&self.bucket)
+                .arg(if unused { "1" } else { "0" })
+                .invoke_async(&mut self.connection)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at TEST_REDIS_URL"]
+    async fn acquire_sends_one_request_until_limits_are_known() {
+        let mut server = Server::connect().await;
+
+        assert_eq!(server.acquire().await, 0);
+
+        let wait = server.acquire().await;
+        assert!(wait > 0 && wait <= POLL_INTERVAL, "waits {}ms", wait);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at TEST_REDIS_URL"]
+    async fn update_hands_out_remaining_tickets() {
+        let mut server = Server::connect().await;
+
+        assert_eq!(server.acquire().await, 0);
+        assert_eq!(server.update(2, 1, 1000).await, 1);
+        assert_eq!(server.acquire().await, 0);
+
+        let wait = server.acquire().await;
+        assert!(wait > 0 && wait <= 1000, "waits {}ms", wait);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at TEST_REDIS_URL"]
+    async fn update_keeps_lowest_remaining_of_window() {
+        let mut server = Server::connect().await;
+
+        assert_eq!(server.acquire().await, 0);
+        assert_eq!(server.update(3, 2, 1000).await, 2);
+        assert_eq!(server.acquire().await, 0);
+
+        // A response sent before the last ticket was taken arrives late
+        assert_eq!(server.update(3, 2, 1000).await, 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at TEST_REDIS_URL"]
+    async fn release_gives_back_unused_tickets() {
+        let mut server = Server::connect().await;
+
+        assert_eq!(server.acquire().await, 0);
+        assert_eq!(server.update(2, 1, 1000).await, 1);
+        assert_eq!(server.acquire().await, 0);
+        assert!(server.acquire().await > 0);
+
+        server.release(true).await;
+        assert_eq!(server.acquire().await, 0);
+
+        // Used tickets stay taken
+        server.release(false).await;
+        assert!(server.acquire().await > 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at TEST_REDIS_URL"]
+    async fn release_without_limits_lets_next_request_learn_them() {
+        let mut server = Server::connect().await;
+
+        assert_eq!(server.acquire().await, 0);
+        assert!(server.acquire().await > 0);
+
+        server.release(false).await;
+        assert_eq!(server.acquire().await, 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at TEST_REDIS_URL"]
+    async fn global_lock_holds_back_requests() {
+        let mut server = Server::connect().await;
+        let _: () = server
+            .connection
+            .pset_ex(&server.global, 1, 500)
+            .await
+            .unwrap();
+
+        let wait = server.acquire().await;
+        assert!(wait > 0 && wait <= 500, "waits {}ms", wait);
+    }
+}
@@ -0,0 +1,59 @@
+use http::{header::RETRY_AFTER, request::Parts, HeaderMap, Method, Uri, Version};
+use hyper::{body::Bytes, Body, Request};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Body Discord sends alongside a 429 response.
+#[derive(Deserialize)]
+struct RatelimitedBody {
+    retry_after: f64,
+}
+
+/// A request whose body has been buffered, so that it can be sent again after
+/// being ratelimited.
+pub struct Replay {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Replay {
+    pub fn new(parts: Parts, body: Bytes) -> Self {
+        Self {
+            method: parts.method,
+            uri: parts.uri,
+            version: parts.version,
+            headers: parts.headers,
+            body,
+        }
+    }
+
+    pub fn request(&self) -> Request<Body> {
+        let mut request = Request::new(Body::from(self.body.clone()));
+        *request.method_mut() = self.method.clone();
+        *request.uri_mut() = self.uri.clone();
+        *request.version_mut() = self.version;
+        *request.headers_mut() = self.headers.clone();
+
+        request
+    }
+}
+
+/// How long to wait before retrying a 429 response, preferring the precise
+/// value in the body over the `Retry-After` header.
+pub fn retry_after(headers: &HeaderMap, body: &[u8]) -> Option<Duration> {
+    if let Ok(body) = serde_json::from_slice::<RatelimitedBody>(body) {
+        // Negative, infinite or overly large values fall back to the header
+        if let Ok(retry_after) = Duration::try_from_secs_f64(body.retry_after) {
+            return Some(retry_after);
+        }
+    }
+
+    headers
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs)
+}
@@ -0,0 +1,85 @@
+use dashmap::DashMap;
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::time::{interval, Duration, Instant};
+use tracing::{debug, warn};
+use twilight_http_ratelimiting::Path;
+
+use crate::config;
+
+/// Tokens that Discord recently rejected with a 401, which are answered by the
+/// proxy itself instead of being sent to Discord again.
+pub struct TokenQuarantine {
+    inner: Arc<DashMap<String, Instant>>,
+}
+
+async fn reap_expired_tokens(map: Arc<DashMap<String, Instant>>) {
+    let mut interval = interval(Duration::from_secs(60));
+
+    loop {
+        interval.tick().await;
+        let right_now = Instant::now();
+
+        map.retain(|_, until| *until > right_now);
+
+        debug!("Done reaping expired token quarantines");
+    }
+}
+
+/// Body Discord sends alongside an error response.
+#[derive(Deserialize)]
+struct ErrorBody {
+    code: u64,
+}
+
+/// Whether the path carries a token of its own, which Discord checks instead
+/// of the one in the `Authorization` header.
+pub fn has_own_token(path: &Path) -> bool {
+    matches!(
+        path,
+        Path::InteractionCallback(..)
+            | Path::WebhooksIdToken(..)
+            | Path::WebhooksIdTokenMessagesId(..)
+    )
+}
+
+/// Whether the body of a 401 response says that the token was rejected, as
+/// opposed to some other credential such as a webhook token.
+pub fn is_token_rejection(body: &[u8]) -> bool {
+    matches!(serde_json::from_slice(body), Ok(ErrorBody { code: 0 }))
+}
+
+impl TokenQuarantine {
+    pub fn new() -> Self {
+        let inner = Arc::new(DashMap::new());
+
+        tokio::spawn(reap_expired_tokens(inner.clone()));
+
+        Self { inner }
+    }
+
+    pub fn insert(&self, token: &str) {
+        let cooldown = config::current().quarantine.cooldown;
+
+        if cooldown == 0 {
+            return;
+        }
+
+        warn!(
+            "Discord rejected a token, quarantining it for {}s",
+            cooldown
+        );
+
+        self.inner.insert(
+            token.to_string(),
+            Instant::now() + Duration::from_secs(cooldown),
+        );
+    }
+
+    /// Returns how much longer the token is quarantined for, if it is.
+    pub fn remaining(&self, token: &str) -> Option<Duration> {
+        let until = *self.inner.get(token)?;
+
+        until.checked_duration_since(Instant::now())
+    }
+}
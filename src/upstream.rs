@@ -0,0 +1,213 @@
+use http::{
+    uri::{Authority, InvalidUri, Scheme},
+    HeaderValue, Uri,
+};
+use serde::Deserialize;
+use std::{
+    convert::TryFrom,
+    error::Error,
+    fmt::{Display, Formatter, Result as FmtResult},
+    str::FromStr,
+};
+
+/// The API the proxy forwards requests to, by default `https://discord.com`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Upstream {
+    scheme: Scheme,
+    authority: Authority,
+    base_path: String,
+}
+
+impl Upstream {
+    pub fn from_url(url: &str) -> Result<Self, UpstreamError> {
+        let uri = Uri::from_str(url).map_err(|source| UpstreamError::InvalidUri { source })?;
+        let parts = uri.into_parts();
+
+        let scheme = match parts.scheme {
+            Some(scheme) if scheme == Scheme::HTTP || scheme == Scheme::HTTPS => scheme,
+            Some(scheme) => {
+                return Err(UpstreamError::UnsupportedScheme {
+                    scheme: scheme.to_string(),
+                })
+            }
+            None => return Err(UpstreamError::MissingScheme),
+        };
+
+        let authority = parts.authority.ok_or(UpstreamError::MissingHost)?;
+
+        let base_path = match parts.path_and_query {
+            Some(path_and_query) => {
+                if path_and_query.query().is_some() {
+                    return Err(UpstreamError::HasQuery);
+                }
+
+                path_and_query.path().trim_end_matches('/').to_owned()
+            }
+            None => String::new(),
+        };
+
+        Ok(Self {
+            scheme,
+            authority,
+            base_path,
+        })
+    }
+
+    pub fn is_https(&self) -> bool {
+        self.scheme == Scheme::HTTPS
+    }
+
+    pub fn host_header(&self) -> HeaderValue {
+        HeaderValue::from_str(self.authority.as_str())
+            .expect("authorities are guaranteed to be valid header values")
+    }
+
+    pub fn uri(&self, api_route: &str, query: Option<&str>) -> Result<Uri, InvalidUri> {
+        let mut uri_string = format!(
+            "{}://{}{}{}",
+            self.scheme, self.authority, self.base_path, api_route
+        );
+
+        if let Some(query) = query {
+            uri_string.push('?');
+            uri_string.push_str(query);
+        }
+
+        Uri::from_str(&uri_string)
+    }
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self {
+            scheme: Scheme::HTTPS,
+            authority: Authority::from_static("discord.com"),
+            base_path: String::new(),
+        }
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = UpstreamError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_url(s)
+    }
+}
+
+impl TryFrom<String> for Upstream {
+    type Error = UpstreamError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::from_url(&value)
+    }
+}
+
+impl Display for Upstream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}://{}{}", self.scheme, self.authority, self.base_path)
+    }
+}
+
+#[derive(Debug)]
+pub enum UpstreamError {
+    HasQuery,
+    InvalidUri { source: InvalidUri },
+    MissingHost,
+    MissingScheme,
+    UnsupportedScheme { scheme: String },
+}
+
+impl Display for UpstreamError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::HasQuery => f.write_str("upstream url must not contain a query"),
+            Self::InvalidUri { source } => {
+                f.write_str("upstream url is invalid: ")?;
+                source.fmt(f)
+            }
+            Self::MissingHost => f.write_str("upstream url is missing a host"),
+            Self::MissingScheme => f.write_str("upstream url is missing a scheme"),
+            Self::UnsupportedScheme { scheme } => {
+                f.write_str("unsupported upstream url scheme: ")?;
+                f.write_str(scheme)
+            }
+        }
+    }
+}
+
+impl Error for UpstreamError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Upstream, UpstreamError};
+
+    #[test]
+    fn trailing_slashes_are_trimmed() {
+        let upstream = Upstream::from_url("http://127.0.0.1:8080/discord//").unwrap();
+
+        assert_eq!(upstream.to_string(), "http://127.0.0.1:8080/discord");
+        assert!(!upstream.is_https());
+        assert_eq!(upstream.host_header(), "127.0.0.1:8080");
+
+        let upstream = Upstream::from_url("https://discord.com/").unwrap();
+        assert_eq!(upstream, Upstream::default());
+    }
+
+    #[test]
+    fn query_is_rejected() {
+        assert!(matches!(
+            Upstream::from_url("https://discord.com/?a=b"),
+            Err(UpstreamError::HasQuery)
+        ));
+    }
+
+    #[test]
+    fn scheme_is_checked() {
+        assert!(matches!(
+            Upstream::from_url("discord.com"),
+            Err(UpstreamError::MissingScheme)
+        ));
+        assert!(matches!(
+            Upstream::from_url("/api"),
+            Err(UpstreamError::MissingScheme)
+        ));
+        assert!(matches!(
+            Upstream::from_url("ftp://discord.com"),
+            Err(UpstreamError::UnsupportedScheme { scheme }) if scheme == "ftp"
+        ));
+    }
+
+    #[test]
+    fn missing_or_invalid_host_is_rejected() {
+        assert!(matches!(
+            Upstream::from_url("http://"),
+            Err(UpstreamError::InvalidUri { .. })
+        ));
+        assert!(matches!(
+            Upstream::from_url("https://disc ord.com"),
+            Err(UpstreamError::InvalidUri { .. })
+        ));
+    }
+
+    #[test]
+    fn uri_joins_base_path_route_and_query() {
+        let upstream = Upstream::from_url("http://localhost:8080/proxy/").unwrap();
+
+        assert_eq!(
+            upstream.uri("/api/v10/users/1", None).unwrap(),
+            "http://localhost:8080/proxy/api/v10/users/1"
+        );
+        assert_eq!(
+            upstream
+                .uri("/api/v10/guilds/1/members", Some("limit=10&after=5"))
+                .unwrap(),
+            "http://localhost:8080/proxy/api/v10/guilds/1/members?limit=10&after=5"
+        );
+        assert_eq!(
+            Upstream::default().uri("/api/gateway", None).unwrap(),
+            "https://discord.com/api/gateway"
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use dashmap::DashMap;
+use std::sync::Arc;
+use tokio::time::{interval, Duration, Instant};
+use tracing::debug;
+
+/// How long a token is considered verified after its last successful
+/// response, in seconds.
+const VERIFIED_FOR: u64 = 60 * 60;
+
+/// Tokens that Discord recently accepted, whose application ID can be trusted.
+///
+/// The application ID is read from the token itself, so a made up token can
+/// claim any application until Discord answers a request made with it.
+pub struct VerifiedTokens {
+    inner: Arc<DashMap<String, Instant>>,
+}
+
+async fn reap_expired_tokens(map: Arc<DashMap<String, Instant>>) {
+    let mut interval = interval(Duration::from_secs(60));
+
+    loop {
+        interval.tick().await;
+        let right_now = Instant::now();
+
+        map.retain(|_, until| *until > right_now);
+
+        debug!("Done reaping expired token verifications");
+    }
+}
+
+impl VerifiedTokens {
+    pub fn new() -> Self {
+        let inner = Arc::new(DashMap::new());
+
+        tokio::spawn(reap_expired_tokens(inner.clone()));
+
+        Self { inner }
+    }
+
+    pub fn insert(&self, token: &str) {
+        self.inner.insert(
+            token.to_string(),
+            Instant::now() + Duration::from_secs(VERIFIED_FOR),
+        );
+    }
+
+    pub fn contains(&self, token: &str) -> bool {
+        matches!(self.inner.get(token), Some(until) if *until > Instant::now())
+    }
+}